@@ -0,0 +1,496 @@
+//! Record pty sessions in the asciicast v2 format, and replay them.
+//!
+//! A cast is a JSON header line followed by one line per event,
+//! `[seconds, code, data]`, where the code is "o" for output, "i" for input,
+//! "r" for a resize ("COLSxROWS") and "m" for a marker.
+//! See <https://docs.asciinema.org/manual/asciicast/v2/>
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::oneshot;
+use crate::test::{PtySize, UnixMasterPty};
+use crate::utf8::Utf8Decoder;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u8,
+    pub width: u16,
+    pub height: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Output,
+    Input,
+    Resize,
+    Marker,
+}
+
+impl EventKind {
+    fn code(self) -> &'static str {
+        match self {
+            EventKind::Output => "o",
+            EventKind::Input => "i",
+            EventKind::Resize => "r",
+            EventKind::Marker => "m",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "o" => Some(EventKind::Output),
+            "i" => Some(EventKind::Input),
+            "r" => Some(EventKind::Resize),
+            "m" => Some(EventKind::Marker),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Seconds since the start of the recording
+    pub time: f64,
+    pub kind: EventKind,
+    pub data: String,
+}
+
+impl Event {
+    /// The new size, for a resize event
+    pub fn size(&self) -> Option<PtySize> {
+        let (cols, rows) = self.data.split_once('x')?;
+        Some(PtySize {
+            rows: rows.parse().ok()?,
+            cols: cols.parse().ok()?,
+            ..PtySize::default()
+        })
+    }
+}
+
+/// Writes a session to an asciicast v2 file as it happens
+pub struct Recorder<W: Write> {
+    writer: W,
+    start: Instant,
+    output: Utf8Decoder,
+    input: Utf8Decoder,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Start a recording, writing the header immediately
+    pub fn new(mut writer: W, size: PtySize) -> Result<Self, failure::Error> {
+        let env = ["TERM", "SHELL"]
+            .iter()
+            .filter_map(|k| std::env::var(k).ok().map(|v| (k.to_string(), v)))
+            .collect();
+
+        let header = Header {
+            version: 2,
+            width: size.cols,
+            height: size.rows,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+            title: None,
+            env: Some(env),
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+
+        Ok(Self { writer, start: Instant::now(), output: Utf8Decoder::lossy(), input: Utf8Decoder::lossy() })
+    }
+
+    pub fn output(&mut self, data: &[u8]) -> Result<(), failure::Error> {
+        self.record(EventKind::Output, data, Instant::now())
+    }
+
+    pub fn input(&mut self, data: &[u8]) -> Result<(), failure::Error> {
+        self.record(EventKind::Input, data, Instant::now())
+    }
+
+    pub fn resize(&mut self, size: PtySize) -> Result<(), failure::Error> {
+        self.record(EventKind::Resize, resize_data(size).as_bytes(), Instant::now())
+    }
+
+    pub fn marker(&mut self, label: &str) -> Result<(), failure::Error> {
+        self.record(EventKind::Marker, label.as_bytes(), Instant::now())
+    }
+
+    /// Write an event that happened `at`
+    fn record(&mut self, kind: EventKind, data: &[u8], at: Instant) -> Result<(), failure::Error> {
+        let text = match kind {
+            EventKind::Output => self.output.push(data)?,
+            EventKind::Input => self.input.push(data)?,
+            EventKind::Resize | EventKind::Marker => String::from_utf8_lossy(data).into_owned(),
+        };
+        if text.is_empty() && kind != EventKind::Marker {
+            return Ok(());
+        }
+        let time = at.saturating_duration_since(self.start).as_secs_f64();
+        serde_json::to_writer(&mut self.writer, &(time, kind.code(), text))?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn resize_data(size: PtySize) -> String {
+    format!("{}x{}", size.cols, size.rows)
+}
+
+/// Wraps a session, recording everything read as output and everything
+/// written as input.
+///
+/// The events are written by a thread of their own, so reads and writes
+/// never wait for the recording.  If writing it fails, the thread stops,
+/// and the error is returned by the next read or write, or by `finish`.
+pub struct Recording<T, W> {
+    inner: T,
+    tx: mpsc::Sender<(EventKind, Vec<u8>, Instant)>,
+    error: Arc<Mutex<Option<failure::Error>>>,
+    done: oneshot::Receiver<W>,
+}
+
+impl<T, W: Write + Send + 'static> Recording<T, W> {
+    /// Start recording, writing the header before this returns
+    pub fn new(inner: T, writer: W, size: PtySize) -> Result<Self, failure::Error> {
+        let mut recorder = Recorder::new(writer, size)?;
+        let (tx, rx) = mpsc::channel::<(EventKind, Vec<u8>, Instant)>();
+        let (done_tx, done) = oneshot::channel();
+        let error = Arc::new(Mutex::new(None));
+
+        let recorder_error = error.clone();
+        std::thread::spawn(move || {
+            for (kind, data, at) in rx {
+                if let Err(e) = recorder.record(kind, &data, at) {
+                    log::error!("Failed to record {:?}: {:?}", kind, e);
+                    *recorder_error.lock().unwrap() = Some(e);
+                    break;
+                }
+            }
+            let _ = done_tx.send(recorder.into_inner());
+        });
+
+        Ok(Self { inner, tx, error, done })
+    }
+}
+
+impl<T, W> Recording<T, W> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn marker(&self, label: &str) -> Result<(), failure::Error> {
+        self.send(EventKind::Marker, label.as_bytes())
+    }
+
+    /// Wait for everything recorded to be written, and return the session
+    /// and the writer
+    pub async fn finish(self) -> Result<(T, W), failure::Error> {
+        let Recording { inner, tx, error, done } = self;
+        drop(tx);
+        let writer = done.await.map_err(|_| failure::err_msg("The recording thread panicked"))?;
+        let error = error.lock().unwrap().take();
+        match error {
+            Some(e) => Err(e),
+            None => Ok((inner, writer)),
+        }
+    }
+
+    fn send(&self, kind: EventKind, data: &[u8]) -> Result<(), failure::Error> {
+        if let Some(e) = self.error.lock().unwrap().take() {
+            return Err(e);
+        }
+        // once the thread has stopped, its error has been returned already
+        let _ = self.tx.send((kind, data.to_vec(), Instant::now()));
+        Ok(())
+    }
+
+    /// The recording error as an io error, for the AsyncRead and AsyncWrite impls
+    fn take_error(&self) -> Option<io::Error> {
+        let error = self.error.lock().unwrap().take();
+        error.map(|e| io::Error::new(io::ErrorKind::Other, e.compat()))
+    }
+}
+
+impl<W> Recording<UnixMasterPty, W> {
+    /// Resize the pty and record the resize
+    pub fn resize(&mut self, size: PtySize) -> Result<(), failure::Error> {
+        self.inner.resize(size)?;
+        self.send(EventKind::Resize, resize_data(size).as_bytes())
+    }
+}
+
+impl UnixMasterPty {
+    /// Record everything that goes through the master
+    pub fn record<W: Write + Send + 'static>(self, writer: W) -> Result<Recording<Self, W>, failure::Error> {
+        let size = self.get_size()?;
+        Recording::new(self, writer, size)
+    }
+}
+
+impl<T: AsyncRead + Unpin, W> AsyncRead for Recording<T, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(e) = this.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let _ = this.tx.send((EventKind::Output, buf.filled()[before..].to_vec(), Instant::now()));
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin, W> AsyncWrite for Recording<T, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(e) = this.take_error() {
+            return Poll::Ready(Err(e));
+        }
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            let _ = this.tx.send((EventKind::Input, buf[..n].to_vec(), Instant::now()));
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A recorded session
+#[derive(Debug, Clone)]
+pub struct Cast {
+    pub header: Header,
+    pub events: Vec<Event>,
+}
+
+impl Cast {
+    pub fn load<R: BufRead>(reader: R) -> Result<Self, failure::Error> {
+        let mut lines = reader.lines();
+        let header = match lines.next() {
+            Some(line) => serde_json::from_str::<Header>(&line?)?,
+            None => return Err(failure::err_msg("Empty asciicast")),
+        };
+        if header.version != 2 {
+            return Err(failure::format_err!("Unsupported asciicast version {}", header.version));
+        }
+
+        let mut events = vec![];
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (time, code, data): (f64, String, String) = serde_json::from_str(&line)?;
+            match EventKind::from_code(&code) {
+                Some(kind) => events.push(Event { time, kind, data }),
+                None => log::debug!("skipping unknown asciicast event {:?}", code),
+            }
+        }
+        Ok(Self { header, events })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
+        Self::load(io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn size(&self) -> PtySize {
+        PtySize { rows: self.header.height, cols: self.header.width, ..PtySize::default() }
+    }
+
+    /// All output concatenated, without timing
+    pub fn output(&self) -> String {
+        self.events
+            .iter()
+            .filter(|e| e.kind == EventKind::Output)
+            .map(|e| e.data.as_str())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    /// Keep the recorded delays
+    Original,
+    /// Play back faster (> 1.0) or slower (< 1.0)
+    Scaled(f64),
+    /// No delays at all
+    Instant,
+}
+
+/// Write the output events of a cast to `writer`, and pass each resize to
+/// `resize` along with it, such as `|master, size| master.resize(size)` for
+/// a `UnixMasterPty`
+pub async fn replay<W, F>(
+    cast: &Cast,
+    writer: &mut W,
+    timing: Timing,
+    mut resize: F,
+) -> Result<(), failure::Error>
+where
+    W: AsyncWrite + Unpin,
+    F: FnMut(&mut W, PtySize) -> Result<(), failure::Error>,
+{
+    let speed = match timing {
+        Timing::Original => Some(1.0),
+        Timing::Scaled(speed) if speed > 0.0 => Some(speed),
+        Timing::Scaled(_) => return Err(failure::err_msg("Replay speed must be positive")),
+        Timing::Instant => None,
+    };
+
+    // sleep until an absolute time, so that delays don't accumulate
+    let start = tokio::time::Instant::now();
+    let events = cast.events.iter().filter(|e| matches!(e.kind, EventKind::Output | EventKind::Resize));
+    for event in events {
+        if let Some(speed) = speed {
+            let at = Duration::from_secs_f64((event.time / speed).max(0.0));
+            tokio::time::sleep_until(start + at).await;
+        }
+        if event.kind == EventKind::Resize {
+            match event.size() {
+                Some(size) => resize(writer, size)?,
+                None => log::warn!("skipping bad asciicast resize {:?}", event.data),
+            }
+            continue;
+        }
+        writer.write_all(event.data.as_bytes()).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() -> Result<(), failure::Error> {
+        let mut recorder = Recorder::new(vec![], PtySize::default())?;
+        // a multibyte character split across two reads
+        let text = "héllo\r\n".as_bytes();
+        recorder.output(&text[..2])?;
+        recorder.output(&text[2..])?;
+        recorder.input(b"q")?;
+        recorder.resize(PtySize { rows: 30, cols: 100, ..PtySize::default() })?;
+        recorder.output(b"wide")?;
+
+        let cast = Cast::load(&recorder.into_inner()[..])?;
+        assert_eq!(cast.size(), PtySize::default());
+        assert_eq!(cast.output(), "héllo\r\nwide");
+        let kinds: Vec<_> = cast.events.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![
+            EventKind::Output,
+            EventKind::Output,
+            EventKind::Input,
+            EventKind::Resize,
+            EventKind::Output,
+        ]);
+        assert_eq!(cast.events[3].size().map(|s| (s.rows, s.cols)), Some((30, 100)));
+
+        // resizes come in order with the output
+        let mut out = vec![];
+        replay(&cast, &mut out, Timing::Scaled(100.0), |out: &mut Vec<u8>, size| {
+            out.extend_from_slice(format!("[{}x{}]", size.cols, size.rows).as_bytes());
+            Ok(())
+        })
+        .await?;
+        assert_eq!(String::from_utf8(out)?, "héllo\r\n[100x30]wide");
+
+        let (mut master, _slave) = crate::test::openpty(PtySize::default())?;
+        replay(&cast, &mut master, Timing::Instant, |master: &mut UnixMasterPty, size| master.resize(size)).await?;
+        assert_eq!(master.get_size()?, PtySize { rows: 30, cols: 100, ..PtySize::default() });
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recording() -> Result<(), failure::Error> {
+        use tokio::io::AsyncReadExt;
+
+        let (master, _slave) = crate::test::openpty(PtySize::default())?;
+        let mut recording = master.record(vec![])?;
+        recording.write_all(b"hi").await?;
+        // the slave echoes the input back
+        let mut echo = [0; 2];
+        recording.read_exact(&mut echo).await?;
+        recording.resize(PtySize { rows: 30, cols: 100, ..PtySize::default() })?;
+        recording.marker("done")?;
+
+        let (_master, cast) = recording.finish().await?;
+        let cast = Cast::load(&cast[..])?;
+        assert_eq!(cast.events[0].kind, EventKind::Input);
+        assert_eq!(cast.events[0].data, "hi");
+        // the echo may come in more than one read
+        assert_eq!(cast.output(), "hi");
+        let last: Vec<_> = cast.events.iter().rev().take(2).map(|e| (e.kind, e.data.as_str())).collect();
+        assert_eq!(last, vec![(EventKind::Marker, "done"), (EventKind::Resize, "100x30")]);
+        Ok(())
+    }
+
+    /// Takes the header, then fails
+    struct Full(bool);
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            self.0 = buf.contains(&b'\n');
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recording_error() -> Result<(), failure::Error> {
+        let (master, _slave) = crate::test::openpty(PtySize::default())?;
+        let mut recording = master.record(Full(false))?;
+        recording.write_all(b"x").await?;
+
+        // the write went through, and recording it fails on the side
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let err = loop {
+            if let Err(e) = recording.write(b"").await {
+                break e;
+            }
+            assert!(Instant::now() < deadline, "no recording error");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert!(err.to_string().contains("disk full"), "{}", err);
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use crate::child::PtyChild;
+use crate::command::PtyCommandBuilder;
+use crate::test::{PtySize, UnixMasterPty};
+
+/// How a process on a pty ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtyExitStatus {
+    /// The exit code, if the process exited normally
+    pub code: Option<i32>,
+    /// The signal that killed the process
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+}
+
+impl PtyExitStatus {
+    pub fn exited(code: i32) -> Self {
+        Self { code: Some(code), signal: None, core_dumped: false }
+    }
+
+    pub fn signaled(signal: i32, core_dumped: bool) -> Self {
+        Self { code: None, signal: Some(signal), core_dumped }
+    }
+
+    /// Decode a status as returned by waitpid(2)
+    pub fn from_raw(status: libc::c_int) -> Self {
+        if libc::WIFSIGNALED(status) {
+            Self::signaled(libc::WTERMSIG(status), libc::WCOREDUMP(status))
+        } else {
+            Self::exited(libc::WEXITSTATUS(status))
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl From<std::process::ExitStatus> for PtyExitStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        Self {
+            code: status.code(),
+            signal: status.signal(),
+            core_dumped: status.core_dumped(),
+        }
+    }
+}
+
+/// Everything a program wrote to the pty, and how it exited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub output: Vec<u8>,
+    pub status: PtyExitStatus,
+}
+
+impl Completion {
+    pub fn output_lossy(&self) -> std::borrow::Cow<str> {
+        String::from_utf8_lossy(&self.output)
+    }
+}
+
+/// A program running on a pty, whatever opened the pty and spawned it.
+///
+/// The program is the leader of a new session with the slave as its
+/// controlling terminal, and this process only keeps the master open,
+/// so `read` returns 0 once the program and everything it started have exited.
+/// See the `conformance` module for the behaviour every backend must share.
+pub trait Pty: Sized {
+    /// Whether this backend can run `command` at all.  `spawn` fails for
+    /// anything this refuses, and every other option must be honored.
+    fn supports(_command: &PtyCommandBuilder) -> bool {
+        true
+    }
+
+    /// Open a pty of `size` and run `command` on it
+    fn spawn(command: &PtyCommandBuilder, size: PtySize) -> Result<Self, failure::Error>;
+
+    /// Read output from the master, blocking until some is available
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write input to the master
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    fn resize(&mut self, size: PtySize) -> Result<(), failure::Error>;
+
+    /// Send a signal to the program
+    fn signal(&mut self, signal: libc::c_int) -> Result<(), failure::Error>;
+
+    /// Wait for the program to exit
+    fn wait(&mut self) -> Result<PtyExitStatus, failure::Error>;
+
+    /// Read until EOF, then reap the program.
+    ///
+    /// EOF only comes once everything holding the slave has closed it, so no
+    /// output is lost to a race with the exit.  A background process that
+    /// keeps the slave open keeps this waiting too.
+    fn run_to_completion(&mut self) -> Result<Completion, failure::Error> {
+        let mut output = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let status = self.wait()?;
+        Ok(Completion { output, status })
+    }
+
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => buf = &buf[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Block until `fd` is ready for `events`
+pub fn poll_fd(fd: RawFd, events: libc::c_short) -> io::Result<()> {
+    let mut pfd = libc::pollfd { fd, events, revents: 0 };
+    loop {
+        if unsafe { libc::poll(&mut pfd, 1, -1) } != -1 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// The tokio backend, driven synchronously.
+///
+/// Must be spawned from within a tokio runtime.  The master is nonblocking,
+/// so reads and writes poll(2) first, and `wait` blocks in waitid(2) rather
+/// than in the runtime, so it works inside async code too, though it blocks
+/// the thread like any other method here.
+pub struct TokioPty {
+    master: UnixMasterPty,
+    child: PtyChild,
+}
+
+impl TokioPty {
+    pub fn master(&self) -> &UnixMasterPty {
+        &self.master
+    }
+
+    pub fn child(&self) -> &PtyChild {
+        &self.child
+    }
+
+    pub fn into_inner(self) -> (UnixMasterPty, PtyChild) {
+        (self.master, self.child)
+    }
+}
+
+impl Pty for TokioPty {
+    fn spawn(command: &PtyCommandBuilder, size: PtySize) -> Result<Self, failure::Error> {
+        // tokio reaps the child, fail rather than panic outside a runtime
+        tokio::runtime::Handle::try_current()?;
+        let (master, child) = command.spawn_pty(size)?;
+        Ok(Self { master, child })
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.master.fd.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_fd(self.master.fd.as_raw_fd(), libc::POLLIN)?;
+                }
+                x => return x,
+            }
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.master.fd.write(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    poll_fd(self.master.fd.as_raw_fd(), libc::POLLOUT)?;
+                }
+                x => return x,
+            }
+        }
+    }
+
+    fn resize(&mut self, size: PtySize) -> Result<(), failure::Error> {
+        self.master.resize(size)
+    }
+
+    fn signal(&mut self, signal: libc::c_int) -> Result<(), failure::Error> {
+        self.child.signal(signal)
+    }
+
+    fn wait(&mut self) -> Result<PtyExitStatus, failure::Error> {
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(status.into());
+            }
+            // block until it has exited, and leave the reaping to tokio
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            let flags = libc::WEXITED | libc::WNOWAIT;
+            if unsafe { libc::waitid(libc::P_PID, self.child.id() as libc::id_t, &mut info, flags) } == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! Run a command on a pty and connect it to this terminal.
+//!
+//!     attach [--detach SEQ] [--] command [args...]
+//!
+//! The terminal is put in raw mode so every key goes to the command, and
+//! resizes are passed on.  Typing the detach sequence (default ^], caret
+//! notation is understood) disconnects, hanging up the command as if its
+//! terminal had been closed.
+
+use pty_test::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Watches input for the detach sequence, holding back a partial match
+/// until it's clear whether it completes
+struct DetachMatcher {
+    seq: Vec<u8>,
+    // for each prefix of `seq`, the length of its longest proper prefix
+    // that's also a suffix, where a failed match falls back to (KMP)
+    fallback: Vec<usize>,
+    matched: usize,
+}
+
+impl DetachMatcher {
+    fn new(seq: Vec<u8>) -> Self {
+        let mut fallback = vec![0; seq.len()];
+        let mut k = 0;
+        for i in 1..seq.len() {
+            while k > 0 && seq[i] != seq[k] {
+                k = fallback[k - 1];
+            }
+            if seq[i] == seq[k] {
+                k += 1;
+            }
+            fallback[i] = k;
+        }
+        Self { seq, fallback, matched: 0 }
+    }
+
+    /// Append the bytes of `input` to forward to `out`, stopping at the end
+    /// of the detach sequence.  Returns true if it was seen.
+    fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) -> bool {
+        for &b in input {
+            if self.seq.is_empty() {
+                out.push(b);
+                continue;
+            }
+            // the held back bytes are `seq[..matched]`, keep the longest
+            // tail of them that can still start a match
+            while self.matched > 0 && b != self.seq[self.matched] {
+                let next = self.fallback[self.matched - 1];
+                out.extend_from_slice(&self.seq[..self.matched - next]);
+                self.matched = next;
+            }
+            if b == self.seq[self.matched] {
+                self.matched += 1;
+                if self.matched == self.seq.len() {
+                    return true;
+                }
+            } else {
+                out.push(b);
+            }
+        }
+        false
+    }
+}
+
+/// `^]` style caret notation for control characters, anything else is literal
+fn parse_sequence(s: &str) -> Vec<u8> {
+    let mut seq = vec![];
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match (b, bytes.clone().next()) {
+            (b'^', Some(c)) => {
+                bytes.next();
+                seq.push(c.to_ascii_uppercase() ^ 0x40);
+            }
+            (b, _) => seq.push(b),
+        }
+    }
+    seq
+}
+
+fn usage() -> failure::Error {
+    failure::format_err!("usage: attach [--detach SEQ] [--] command [args...]")
+}
+
+async fn attach(builder: PtyCommandBuilder, detach: Vec<u8>) -> Result<i32, failure::Error> {
+    // stdin may be a pipe, then there's no size to copy or mode to change
+    let tty = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    let size = if tty { get_size_fd(libc::STDIN_FILENO)? } else { PtySize::default() };
+    let (mut master, mut child) = builder.spawn_pty(size)?;
+    log::info!("attached to {}", child.id());
+
+    let raw_mode = if tty { Some(RawModeGuard::new(libc::STDIN_FILENO)?) } else { None };
+
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut winch = signal(SignalKind::window_change())?;
+    let mut matcher = DetachMatcher::new(detach);
+    let mut input = [0; 1024];
+    let mut output = [0; 4096];
+    let mut stdin_done = false;
+    let mut detached = false;
+
+    loop {
+        tokio::select! {
+            n = master.read(&mut output) => {
+                match n {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        stdout.write_all(&output[..n]).await?;
+                        stdout.flush().await?;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to read from the pty: {:?}", e);
+                        break;
+                    }
+                }
+            }
+
+            n = stdin.read(&mut input), if !stdin_done => {
+                match n? {
+                    0 => stdin_done = true,
+                    n => {
+                        let mut forward = vec![];
+                        detached = matcher.feed(&input[..n], &mut forward);
+                        master.write_all(&forward).await?;
+                        if detached {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            _ = winch.recv(), if tty => {
+                match get_size_fd(libc::STDIN_FILENO) {
+                    Ok(size) => master.resize(size)?,
+                    Err(e) => log::warn!("Failed to get the terminal size: {:?}", e),
+                }
+            }
+        }
+    }
+
+    drop(raw_mode);
+    if detached {
+        eprintln!("[detached]");
+        child.signal(libc::SIGHUP)?;
+    }
+    drop(master);
+
+    let status = PtyExitStatus::from(child.wait().await?);
+    log::info!("child status: {:?}", status);
+    Ok(match (status.code, status.signal) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    })
+}
+
+fn main() -> Result<(), failure::Error> {
+    env_logger::init();
+
+    let mut args = std::env::args().skip(1).peekable();
+    let mut detach = parse_sequence("^]");
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "--detach" | "-d" => {
+                args.next();
+                detach = parse_sequence(&args.next().ok_or_else(usage)?);
+            }
+            "--" => {
+                args.next();
+                break;
+            }
+            _ => break,
+        }
+    }
+    let program = args.next().ok_or_else(usage)?;
+    let mut builder = PtyCommandBuilder::new(program);
+    builder.args(args);
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let result = rt.block_on(attach(builder, detach));
+    // a read of stdin may still be blocked in a runtime thread
+    rt.shutdown_background();
+    std::process::exit(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(seq: &str, inputs: &[&str]) -> (String, bool) {
+        let mut matcher = DetachMatcher::new(seq.as_bytes().to_vec());
+        let mut out = vec![];
+        for input in inputs {
+            if matcher.feed(input.as_bytes(), &mut out) {
+                return (String::from_utf8(out).unwrap(), true);
+            }
+        }
+        (String::from_utf8(out).unwrap(), false)
+    }
+
+    #[test]
+    fn test_detach_matcher() {
+        assert_eq!(feed_all("aab", &["aaab"]), ("a".to_owned(), true));
+        assert_eq!(feed_all("aab", &["a", "a", "a", "b"]), ("a".to_owned(), true));
+        assert_eq!(feed_all("abac", &["xababac", "never sent"]), ("xab".to_owned(), true));
+        assert_eq!(feed_all("aab", &["aax"]), ("aax".to_owned(), false));
+        // a partial match is held back until the next input
+        assert_eq!(feed_all("ab", &["xa"]), ("x".to_owned(), false));
+        assert_eq!(feed_all("ab", &["xa", "c"]), ("xac".to_owned(), false));
+        assert_eq!(feed_all("", &["ab"]), ("ab".to_owned(), false));
+    }
+
+    #[test]
+    fn test_parse_sequence() {
+        assert_eq!(parse_sequence("^]"), vec![0x1d]);
+        assert_eq!(parse_sequence("^a^A"), vec![1, 1]);
+        assert_eq!(parse_sequence("^?"), vec![0x7f]);
+        assert_eq!(parse_sequence("x^Cy"), b"x\x03y".to_vec());
+        assert_eq!(parse_sequence("~."), b"~.".to_vec());
+        assert_eq!(parse_sequence("a^"), b"a^".to_vec());
+    }
+}
@@ -0,0 +1,206 @@
+use filedescriptor::FileDescriptor;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use tokio::io::AsyncReadExt;
+use crate::backend::Completion;
+use crate::termios::{ControlChar, Termios};
+use crate::test::{PtyFd, UnixMasterPty};
+
+/// A child running on a pty as the leader of its own session.
+///
+/// Keeps a duplicate of the master so that it can query and signal the
+/// foreground job of the session, which is not necessarily the child itself;
+/// an interactive shell puts each command it runs in its own process group.
+#[derive(Debug)]
+pub struct PtyChild {
+    pub child: tokio::process::Child,
+    /// Our ends of the streams that are not on the pty, see `StdioMode`
+    pub stdin: Option<PtyFd>,
+    pub stdout: Option<PtyFd>,
+    pub stderr: Option<PtyFd>,
+    pid: libc::pid_t,
+    master: FileDescriptor,
+}
+
+impl PtyChild {
+    pub fn new(child: tokio::process::Child, master: &UnixMasterPty) -> Result<Self, failure::Error> {
+        let pid = child.id().ok_or_else(|| failure::err_msg("Child has already exited"))? as libc::pid_t;
+        let master = master.fd.try_clone()?;
+        Ok(Self {
+            child,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            pid,
+            master,
+        })
+    }
+
+    /// The pid of the child, which is also its session id and process group
+    pub fn id(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// The session id of the child, as reported by getsid(2)
+    pub fn session_id(&self) -> Result<libc::pid_t, failure::Error> {
+        let sid = unsafe { libc::getsid(self.pid) };
+        if sid == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(sid)
+    }
+
+    /// The foreground process group of the pty
+    pub fn foreground_process_group(&self) -> Result<libc::pid_t, failure::Error> {
+        let pgrp = unsafe { libc::tcgetpgrp(self.master.as_raw_fd()) };
+        if pgrp == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(pgrp)
+    }
+
+    /// Send a signal to the child only
+    pub fn signal(&self, signal: libc::c_int) -> Result<(), failure::Error> {
+        if unsafe { libc::kill(self.pid, signal) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Send a signal to every process in the foreground process group
+    pub fn signal_foreground(&self, signal: libc::c_int) -> Result<(), failure::Error> {
+        let pgrp = self.foreground_process_group()?;
+        if unsafe { libc::killpg(pgrp, signal) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// SIGINT the foreground job
+    pub fn interrupt(&self) -> Result<(), failure::Error> {
+        self.signal_foreground(libc::SIGINT)
+    }
+
+    /// SIGTSTP the foreground job
+    pub fn suspend(&self) -> Result<(), failure::Error> {
+        self.signal_foreground(libc::SIGTSTP)
+    }
+
+    /// SIGCONT the foreground job
+    pub fn resume(&self) -> Result<(), failure::Error> {
+        self.signal_foreground(libc::SIGCONT)
+    }
+
+    /// Write a control character to the master, as if it was typed.
+    /// The line discipline turns it into a signal if ISIG is enabled,
+    /// otherwise the program reads it like any other input.
+    pub fn send_control(&self, c: ControlChar) -> Result<(), failure::Error> {
+        let termios = Termios::from_fd(self.master.as_raw_fd())?;
+        let byte = termios.control_char(c);
+        if byte == libc::_POSIX_VDISABLE as u8 {
+            return Err(failure::format_err!("{:?} is disabled on this pty", c));
+        }
+
+        let n = unsafe { libc::write(self.master.as_raw_fd(), &byte as *const u8 as *const _, 1) };
+        if n != 1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Type the VINTR character, usually ^C
+    pub fn send_intr(&self) -> Result<(), failure::Error> {
+        self.send_control(ControlChar::Intr)
+    }
+
+    /// Type the VSUSP character, usually ^Z
+    pub fn send_susp(&self) -> Result<(), failure::Error> {
+        self.send_control(ControlChar::Susp)
+    }
+
+    /// Read everything from `master` until EOF, then reap the child.
+    /// Reading first means nothing the child wrote just before exiting is
+    /// lost, see `Pty::run_to_completion`.
+    pub async fn run_to_completion(&mut self, master: &mut UnixMasterPty) -> Result<Completion, failure::Error> {
+        let mut output = vec![];
+        master.read_to_end(&mut output).await?;
+        let status = self.wait().await?;
+        Ok(Completion { output, status: status.into() })
+    }
+
+    pub async fn wait(&mut self) -> io::Result<std::process::ExitStatus> {
+        self.child.wait().await
+    }
+
+    pub fn try_wait(&mut self) -> io::Result<Option<std::process::ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    pub async fn kill(&mut self) -> io::Result<()> {
+        self.child.kill().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PtyCommandBuilder;
+    use crate::test::PtySize;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+
+    async fn read_until(master: &mut UnixMasterPty, needle: &str) -> String {
+        let mut out = vec![];
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&out).contains(needle) {
+            let n = tokio::time::timeout(Duration::from_secs(10), master.read(&mut buf)).await.unwrap().unwrap();
+            assert!(n > 0, "EOF before {:?} in {:?}", needle, String::from_utf8_lossy(&out));
+            out.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Wait for the shell to hand the terminal to a job
+    async fn job_in_foreground(child: &PtyChild) -> libc::pid_t {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let pgrp = child.foreground_process_group().unwrap();
+            if pgrp != child.id() {
+                return pgrp;
+            }
+            assert!(Instant::now() < deadline, "no job in the foreground");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_control() -> Result<(), failure::Error> {
+        // with job control, the shell runs each command in its own process
+        // group; the trap keeps it going when a job dies of SIGINT
+        let mut command = PtyCommandBuilder::new("sh");
+        command.arg("-c").arg("set -m; trap : INT; sleep 30; echo after $?; sleep 30; echo after $?; sleep 30; echo after $?; read x");
+        let (mut master, mut child) = command.spawn_pty(PtySize::default())?;
+        assert_eq!(child.session_id()?, child.id());
+
+        // the job gets the signal, not the shell
+        job_in_foreground(&child).await;
+        child.interrupt()?;
+        read_until(&mut master, "after 130\r\n").await;
+
+        // through the line discipline
+        job_in_foreground(&child).await;
+        child.send_intr()?;
+        read_until(&mut master, "after 130\r\n").await;
+
+        let job = job_in_foreground(&child).await;
+        child.send_susp()?;
+        read_until(&mut master, "after 148\r\n").await;
+        // the stopped job is left behind, the shell is back in the foreground
+        assert_eq!(child.foreground_process_group()?, child.id());
+        assert_eq!(unsafe { libc::killpg(job, libc::SIGKILL) }, 0);
+        master.write_all(b"\n").await?;
+
+        assert!(child.wait().await?.success());
+        Ok(())
+    }
+}
@@ -0,0 +1,499 @@
+use filedescriptor::{socketpair, Pipe};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use crate::backend::Completion;
+use crate::child::PtyChild;
+use crate::openpt::openpt;
+use crate::test::{openpty, PreExec, PtyFd, PtySize, UnixMasterPty, UnixSlavePty};
+
+/// Where one of the child's standard streams goes
+#[derive(Debug)]
+pub enum StdioMode {
+    /// The pty slave, the default for all three
+    Pty,
+    /// A pipe, with our end on `PtyChild`
+    Pipe,
+    /// A socketpair, with our end on `PtyChild`
+    SocketPair,
+    /// /dev/null
+    Null,
+    /// Whatever this process has
+    Inherit,
+    /// Our own descriptor, duplicated for each spawn
+    Fd(OwnedFd),
+}
+
+impl StdioMode {
+    /// The child's end, and ours if there is one.
+    /// `input` is true for stdin, which the child reads from.
+    /// Without a slave, the child opens the pty itself and replaces the
+    /// placeholder we give it.
+    fn setup(&self, slave: Option<&UnixSlavePty>, input: bool) -> Result<(Stdio, Option<PtyFd>), failure::Error> {
+        Ok(match self {
+            StdioMode::Pty => match slave {
+                Some(slave) => (slave.fd.as_stdio()?, None),
+                None => (Stdio::inherit(), None),
+            },
+            StdioMode::Pipe => {
+                let pipe = Pipe::new()?;
+                let (ours, theirs) = if input {
+                    (pipe.write, pipe.read)
+                } else {
+                    (pipe.read, pipe.write)
+                };
+                (theirs.as_stdio()?, Some(PtyFd::from_fd_async(ours)?))
+            }
+            StdioMode::SocketPair => {
+                let (ours, theirs) = socketpair()?;
+                (theirs.as_stdio()?, Some(PtyFd::from_fd_async(ours)?))
+            }
+            StdioMode::Null => (Stdio::null(), None),
+            StdioMode::Inherit => (Stdio::inherit(), None),
+            StdioMode::Fd(fd) => (Stdio::from(fd.try_clone()?), None),
+        })
+    }
+}
+
+/// Our ends of the child's streams that are pipes or socketpairs.
+/// They are async, so they must be used from within a tokio runtime.
+#[derive(Debug, Default)]
+pub struct ChildStdio {
+    pub stdin: Option<PtyFd>,
+    pub stdout: Option<PtyFd>,
+    pub stderr: Option<PtyFd>,
+}
+
+/// Describes a program to run on a pty.
+///
+/// Unlike `tokio::process::Command`, the builder only records what to do,
+/// so it can be cloned and spawned any number of times.  The environment
+/// operations are applied in the order they were added, after the optional
+/// `env_clear`, and `TERM` is applied last.
+#[derive(Debug, Clone)]
+pub struct PtyCommandBuilder {
+    program: OsString,
+    args: Vec<OsString>,
+    argv0: Option<OsString>,
+    env_clear: bool,
+    envs: Vec<(OsString, Option<OsString>)>,
+    cwd: Option<PathBuf>,
+    umask: Option<libc::mode_t>,
+    fds: Vec<(RawFd, RawFd)>,
+    term: Option<OsString>,
+    kill_on_drop: bool,
+    // shared so that the builder stays cloneable with `StdioMode::Fd`
+    stdin: Arc<StdioMode>,
+    stdout: Arc<StdioMode>,
+    stderr: Arc<StdioMode>,
+}
+
+impl PtyCommandBuilder {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Self {
+            program: program.as_ref().to_owned(),
+            args: vec![],
+            argv0: None,
+            env_clear: false,
+            envs: vec![],
+            cwd: None,
+            umask: None,
+            fds: vec![],
+            term: Some("xterm-256color".into()),
+            kill_on_drop: true,
+            stdin: Arc::new(StdioMode::Pty),
+            stdout: Arc::new(StdioMode::Pty),
+            stderr: Arc::new(StdioMode::Pty),
+        }
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Set the name the program sees as `argv[0]`
+    pub fn argv0<S: AsRef<OsStr>>(&mut self, argv0: S) -> &mut Self {
+        self.argv0 = Some(argv0.as_ref().to_owned());
+        self
+    }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, value: V) -> &mut Self {
+        self.envs.push((key.as_ref().to_owned(), Some(value.as_ref().to_owned())));
+        self
+    }
+
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        self.envs.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    /// Start from an empty environment, discarding anything set so far
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.env_clear = true;
+        self.envs.clear();
+        self
+    }
+
+    pub fn cwd<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.cwd = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    pub fn umask(&mut self, mask: libc::mode_t) -> &mut Self {
+        self.umask = Some(mask);
+        self
+    }
+
+    /// Pass `fd` from this process to the child as `target`, for example a
+    /// socket as fd 3 for a control channel.  All other descriptors above
+    /// stderr are closed in the child.  `fd` must stay open until `spawn` returns.
+    pub fn inherit_fd(&mut self, fd: RawFd, target: RawFd) -> &mut Self {
+        self.fds.push((fd, target));
+        self
+    }
+
+    /// Set `TERM` for the child, or leave it alone with `None`.
+    /// Defaults to `xterm-256color`.
+    pub fn term<S: AsRef<OsStr>>(&mut self, term: Option<S>) -> &mut Self {
+        self.term = term.map(|t| t.as_ref().to_owned());
+        self
+    }
+
+    /// Kill the child when the handle is dropped, defaults to true
+    pub fn kill_on_drop(&mut self, kill_on_drop: bool) -> &mut Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    /// Where stdin comes from, the pty by default
+    pub fn stdin(&mut self, mode: StdioMode) -> &mut Self {
+        self.stdin = Arc::new(mode);
+        self
+    }
+
+    /// Where stdout goes, the pty by default
+    pub fn stdout(&mut self, mode: StdioMode) -> &mut Self {
+        self.stdout = Arc::new(mode);
+        self
+    }
+
+    /// Where stderr goes, the pty by default.  Capture it separately with
+    /// `StdioMode::Pipe` while stdout stays a tty, for programs that only
+    /// colourize when writing to a terminal.
+    pub fn stderr(&mut self, mode: StdioMode) -> &mut Self {
+        self.stderr = Arc::new(mode);
+        self
+    }
+
+    pub fn get_program(&self) -> &OsStr {
+        &self.program
+    }
+
+    pub fn get_args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    pub fn get_cwd(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+
+    pub fn get_env_clear(&self) -> bool {
+        self.env_clear
+    }
+
+    /// The environment operations in the order they apply, `None` removes
+    /// the variable.  Includes `TERM`, for backends that build their own command.
+    pub fn get_envs(&self) -> Vec<(OsString, Option<OsString>)> {
+        let mut envs = self.envs.clone();
+        if let Some(term) = &self.term {
+            envs.push(("TERM".into(), Some(term.clone())));
+        }
+        envs
+    }
+
+    /// Build a `std::process::Command` with the program, arguments,
+    /// environment and working directory, but no stdio
+    pub fn as_std_command(&self) -> std::process::Command {
+        let mut cmd = std::process::Command::new(&self.program);
+        cmd.args(&self.args);
+
+        if let Some(argv0) = &self.argv0 {
+            cmd.arg0(argv0);
+        }
+
+        if self.env_clear {
+            cmd.env_clear();
+        }
+
+        for (key, value) in self.get_envs() {
+            match value {
+                Some(value) => cmd.env(key, value),
+                None => cmd.env_remove(key),
+            };
+        }
+
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+
+        cmd
+    }
+
+    /// Build the `tokio::process::Command` with stdio set up as configured,
+    /// returning our ends of the streams that are not on the pty
+    pub fn as_command(&self, slave: &UnixSlavePty) -> Result<(tokio::process::Command, ChildStdio), failure::Error> {
+        let (cmd, stdio, _) = self.build(Some(slave))?;
+        Ok((cmd, stdio))
+    }
+
+    /// Also returns which of stdin, stdout and stderr are on the pty
+    fn build(&self, slave: Option<&UnixSlavePty>) -> Result<(tokio::process::Command, ChildStdio, [bool; 3]), failure::Error> {
+        let mut cmd = tokio::process::Command::from(self.as_std_command());
+        cmd.kill_on_drop(self.kill_on_drop);
+
+        let (stdin, our_stdin) = self.stdin.setup(slave, true)?;
+        let (stdout, our_stdout) = self.stdout.setup(slave, false)?;
+        let (stderr, our_stderr) = self.stderr.setup(slave, false)?;
+        cmd.stdin(stdin);
+        cmd.stdout(stdout);
+        cmd.stderr(stderr);
+
+        let stdio = ChildStdio {
+            stdin: our_stdin,
+            stdout: our_stdout,
+            stderr: our_stderr,
+        };
+        let on_pty = [&self.stdin, &self.stdout, &self.stderr].map(|mode| matches!(**mode, StdioMode::Pty));
+        Ok((cmd, stdio, on_pty))
+    }
+
+    pub fn get_argv0(&self) -> Option<&OsStr> {
+        self.argv0.as_deref()
+    }
+
+    pub fn get_umask(&self) -> Option<libc::mode_t> {
+        self.umask
+    }
+
+    /// The descriptors passed with `inherit_fd`, as (ours, the child's)
+    pub fn get_inherited_fds(&self) -> &[(RawFd, RawFd)] {
+        &self.fds
+    }
+
+    pub fn get_stdin(&self) -> &StdioMode {
+        &self.stdin
+    }
+
+    pub fn get_stdout(&self) -> &StdioMode {
+        &self.stdout
+    }
+
+    pub fn get_stderr(&self) -> &StdioMode {
+        &self.stderr
+    }
+
+    /// Whether stdin, stdout and stderr are all on the pty, the only
+    /// setup a backend without its own stdio handling can run
+    pub fn stdio_is_pty(&self) -> bool {
+        [&self.stdin, &self.stdout, &self.stderr].iter().all(|mode| matches!(***mode, StdioMode::Pty))
+    }
+
+    /// The umask and inherited descriptors, for backends that fork themselves
+    pub fn get_pre_exec(&self) -> PreExec {
+        PreExec {
+            umask: self.umask,
+            fds: self.fds.clone(),
+        }
+    }
+
+    /// Spawn the program with the slave as its controlling terminal,
+    /// even if none of its stdio is on the pty.
+    /// The slave can be dropped once this returns.
+    pub fn spawn(&self, slave: &UnixSlavePty) -> Result<(tokio::process::Child, ChildStdio), failure::Error> {
+        let (cmd, stdio) = self.as_command(slave)?;
+        let child = slave.fd.spawn_command_with(cmd, self.get_pre_exec())?;
+        Ok((child, stdio))
+    }
+
+    /// Open a new pty and spawn the program on it.
+    /// The slave is closed in this process, so reads from the master see
+    /// EOF once the child and everything it started have exited.
+    pub fn spawn_pty(&self, size: PtySize) -> Result<(UnixMasterPty, PtyChild), failure::Error> {
+        let (master, slave) = openpty(size)?;
+        let (child, stdio) = self.spawn(&slave)?;
+        drop(slave);
+        pty_child(master, child, stdio)
+    }
+
+    /// Like `spawn_pty`, but the pty comes from `openpt` and this process
+    /// never opens the slave; the child opens it by path after setsid.
+    pub fn spawn_pty_lazy(&self, size: PtySize) -> Result<(UnixMasterPty, PtyChild), failure::Error> {
+        let master = openpt(size)?;
+        let (cmd, stdio, on_pty) = self.build(None)?;
+        let child = master.spawn_command_lazy(cmd, on_pty, self.get_pre_exec())?;
+        pty_child(master, child, stdio)
+    }
+
+    /// Run the program on a new pty and collect all its output and its exit status
+    pub async fn run_to_completion(&self, size: PtySize) -> Result<Completion, failure::Error> {
+        let (mut master, mut child) = self.spawn_pty(size)?;
+        child.run_to_completion(&mut master).await
+    }
+}
+
+fn pty_child(
+    master: UnixMasterPty,
+    child: tokio::process::Child,
+    stdio: ChildStdio,
+) -> Result<(UnixMasterPty, PtyChild), failure::Error> {
+    let mut child = PtyChild::new(child, &master)?;
+    child.stdin = stdio.stdin;
+    child.stdout = stdio.stdout;
+    child.stderr = stdio.stderr;
+    Ok((master, child))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Run `command` on a new pty, returning its output and whether it succeeded
+    async fn run(command: &PtyCommandBuilder) -> Result<(String, bool), failure::Error> {
+        let (mut master, slave) = openpty(PtySize::default())?;
+        let (mut child, _stdio) = command.spawn(&slave)?;
+        drop(slave);
+        let mut output = vec![];
+        master.fd.read_to_end(&mut output).await?;
+        let status = child.wait().await?;
+        Ok((String::from_utf8(output)?, status.success()))
+    }
+
+    #[tokio::test]
+    async fn test_builder_options() -> Result<(), failure::Error> {
+        let mut command = PtyCommandBuilder::new("sh");
+        command
+            .arg("-c")
+            .arg("tr '\\0' ' ' </proc/$$/cmdline; echo; umask; echo ${FOO-unset} ${BAR-unset} $TERM; pwd")
+            .argv0("custom-name")
+            .umask(0o077)
+            .env("FOO", "foo")
+            .env("BAR", "bar")
+            .env_remove("BAR")
+            .cwd("/");
+        let (output, success) = run(&command).await?;
+        let lines = output.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("custom-name -c "), "{:?}", output);
+        assert_eq!(&lines[1..], ["0077", "foo unset xterm-256color", "/"]);
+        assert!(success);
+
+        let mut command = PtyCommandBuilder::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg("echo ${FOO-unset} ${HOME-unset} ${TERM-unset}")
+            .env("HOME", "/")
+            .env_clear()
+            .env("FOO", "foo")
+            .term(None::<&str>);
+        assert_eq!(run(&command).await?.0, "foo unset unset\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_program() -> Result<(), failure::Error> {
+        let (_master, slave) = openpty(PtySize::default())?;
+        let command = PtyCommandBuilder::new("/nonexistent/program");
+        assert!(command.spawn(&slave).is_err());
+        assert!(command.spawn_pty(PtySize::default()).is_err());
+        assert!(command.spawn_pty_lazy(PtySize::default()).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_only_inherited_fds_reach_the_child() -> Result<(), failure::Error> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut pipe = filedescriptor::Pipe::new()?;
+        // a descriptor that isn't close-on-exec, which would leak without the cleanup
+        let stray = unsafe { libc::dup(0) };
+        assert!(stray > 2);
+
+        let mut command = PtyCommandBuilder::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("echo hi >&3; [ -e /dev/fd/{} ] && echo leaked || echo closed", stray))
+            .inherit_fd(pipe.write.as_raw_fd(), 3);
+        let result = run(&command).await;
+        drop(pipe.write);
+        unsafe { libc::close(stray) };
+
+        let (output, success) = result?;
+        assert_eq!(output, "closed\r\n");
+        assert!(success);
+        let mut inherited = String::new();
+        std::io::Read::read_to_string(&mut pipe.read, &mut inherited)?;
+        assert_eq!(inherited, "hi\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stdio_modes() -> Result<(), failure::Error> {
+        // stdin from a pipe, stderr to a socketpair, and stdout on the pty
+        let mut command = PtyCommandBuilder::new("sh");
+        command
+            .arg("-c")
+            .arg("read x; echo out $x; echo err $x >&2; [ -t 0 ] || echo piped; tty -s </dev/tty && echo ctty")
+            .stdin(StdioMode::Pipe)
+            .stderr(StdioMode::SocketPair);
+        let (mut master, mut child) = command.spawn_pty(PtySize::default())?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"hi\n").await?;
+        drop(stdin);
+        let mut stderr = String::new();
+        AsyncReadExt::read_to_string(&mut child.stderr.take().unwrap(), &mut stderr).await?;
+        assert_eq!(stderr, "err hi\n");
+        let completion = child.run_to_completion(&mut master).await?;
+        assert_eq!(completion.output_lossy(), "out hi\r\npiped\r\nctty\r\n");
+
+        // stdout to /dev/null, stderr left as ours
+        let mut command = PtyCommandBuilder::new("sh");
+        command
+            .arg("-c")
+            .arg("echo hidden; readlink /proc/$$/fd/2 >/dev/tty")
+            .stdout(StdioMode::Null)
+            .stderr(StdioMode::Inherit);
+        let completion = command.run_to_completion(PtySize::default()).await?;
+        let ours = std::fs::read_link("/proc/self/fd/2")?;
+        assert_eq!(completion.output_lossy(), format!("{}\r\n", ours.display()));
+
+        // stdout to a descriptor of ours
+        let mut pipe = Pipe::new()?;
+        let write = unsafe { OwnedFd::from_raw_fd(pipe.write.into_raw_fd()) };
+        let mut command = PtyCommandBuilder::new("echo");
+        command.arg("through the pipe").stdout(StdioMode::Fd(write));
+        let completion = command.run_to_completion(PtySize::default()).await?;
+        assert_eq!(completion.output_lossy(), "");
+        // the builder still holds its copy
+        drop(command);
+        let mut output = String::new();
+        std::io::Read::read_to_string(&mut pipe.read, &mut output)?;
+        assert_eq!(output, "through the pipe\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! Behaviour every `Pty` backend must share.
+//!
+//! Each check spawns its own programs and returns an error describing the
+//! first thing that went wrong.  A backend's tests call `run_all`, or the
+//! checks one by one to see which fail:
+//!
+//! ```ignore
+//! #[test]
+//! fn conformance() {
+//!     pty_test::conformance::run_all::<MyPty>().unwrap();
+//! }
+//! ```
+
+use failure::{bail, ensure};
+use crate::backend::{Pty, PtyExitStatus};
+use crate::command::{PtyCommandBuilder, StdioMode};
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use crate::test::PtySize;
+
+/// Read until `needle` appears in the output, returning everything read
+pub fn read_until<P: Pty>(pty: &mut P, needle: &str) -> Result<String, failure::Error> {
+    let mut output = vec![];
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = pty.read(&mut buf)?;
+        if n == 0 {
+            bail!("EOF before {:?}, read {:?}", needle, String::from_utf8_lossy(&output));
+        }
+        output.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&output);
+        if text.contains(needle) {
+            return Ok(text.into_owned());
+        }
+    }
+}
+
+/// Read until EOF
+pub fn read_to_end<P: Pty>(pty: &mut P) -> Result<String, failure::Error> {
+    let mut output = vec![];
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = pty.read(&mut buf)?;
+        if n == 0 {
+            return Ok(String::from_utf8_lossy(&output).into_owned());
+        }
+        output.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn sh(script: &str) -> PtyCommandBuilder {
+    let mut command = PtyCommandBuilder::new("sh");
+    command.arg("-c").arg(script);
+    command
+}
+
+/// Input written to the master comes back from `cat`, and ^D ends it
+pub fn echo_via_cat<P: Pty>() -> Result<(), failure::Error> {
+    // without the line discipline's echo, cat is the only one to send it back
+    let mut pty = P::spawn(&sh("stty -echo; echo ready; exec cat"), PtySize::default())?;
+    read_until(&mut pty, "ready\r\n")?;
+    pty.write_all(b"hello pty\n")?;
+    let output = read_until(&mut pty, "hello pty\r\n")?;
+    ensure!(output == "hello pty\r\n", "unexpected output {:?}", output);
+
+    // ^D at the start of a line is EOF in canonical mode
+    pty.write_all(b"\x04")?;
+    read_to_end(&mut pty)?;
+    let status = pty.wait()?;
+    ensure!(status.success(), "cat exited with {:?}", status);
+    Ok(())
+}
+
+/// The program's stdin is a pty slave
+pub fn tty_reports_pts_path<P: Pty>() -> Result<(), failure::Error> {
+    let mut pty = P::spawn(&PtyCommandBuilder::new("tty"), PtySize::default())?;
+    let output = read_to_end(&mut pty)?;
+    let path = output.trim();
+    ensure!(
+        path.starts_with("/dev/pts/") || path.starts_with("/dev/tty"),
+        "tty printed {:?}",
+        output
+    );
+    let status = pty.wait()?;
+    ensure!(status.success(), "tty exited with {:?}", status);
+    Ok(())
+}
+
+/// Exit codes are reported as they are
+pub fn exit_codes<P: Pty>() -> Result<(), failure::Error> {
+    for &code in &[0, 1, 3, 42, 255] {
+        let mut pty = P::spawn(&sh(&format!("exit {}", code)), PtySize::default())?;
+        read_to_end(&mut pty)?;
+        let status = pty.wait()?;
+        ensure!(
+            status == PtyExitStatus::exited(code),
+            "expected exit code {}, got {:?}",
+            code,
+            status
+        );
+    }
+    Ok(())
+}
+
+/// Reads return everything the program wrote, then 0 once it has exited
+pub fn eof_on_exit<P: Pty>() -> Result<(), failure::Error> {
+    let mut pty = P::spawn(&sh("echo first; echo last"), PtySize::default())?;
+    let output = read_to_end(&mut pty)?;
+    ensure!(output == "first\r\nlast\r\n", "unexpected output {:?}", output);
+    pty.wait()?;
+    Ok(())
+}
+
+/// The program sees the new size after a resize
+pub fn resize<P: Pty>() -> Result<(), failure::Error> {
+    let mut pty = P::spawn(&sh("read line; stty size"), PtySize::default())?;
+    pty.resize(PtySize { rows: 30, cols: 100, ..PtySize::default() })?;
+    pty.write_all(b"\n")?;
+    let output = read_to_end(&mut pty)?;
+    ensure!(output.contains("30 100"), "stty size printed {:?}", output);
+    pty.wait()?;
+    Ok(())
+}
+
+/// A signal is reported as the cause of death
+pub fn signal<P: Pty>() -> Result<(), failure::Error> {
+    let mut pty = P::spawn(&sh("echo ready; exec sleep 30"), PtySize::default())?;
+    read_until(&mut pty, "ready")?;
+    pty.signal(libc::SIGTERM)?;
+    read_to_end(&mut pty)?;
+    let status = pty.wait()?;
+    ensure!(status.signal == Some(libc::SIGTERM), "expected SIGTERM, got {:?}", status);
+    Ok(())
+}
+
+/// Output written just before the exit is all there, along with the exit code
+pub fn run_to_completion<P: Pty>() -> Result<(), failure::Error> {
+    let mut pty = P::spawn(&sh("seq 1 5000; exit 7"), PtySize::default())?;
+    let completion = pty.run_to_completion()?;
+    let output = completion.output_lossy();
+    ensure!(output.lines().count() == 5000, "expected 5000 lines, got {}", output.lines().count());
+    let tail = &completion.output[completion.output.len().saturating_sub(20)..];
+    ensure!(output.ends_with("\r\n5000\r\n"), "output ends with {:?}", String::from_utf8_lossy(tail));
+    ensure!(completion.status == PtyExitStatus::exited(7), "expected exit code 7, got {:?}", completion.status);
+    Ok(())
+}
+
+/// Spawn `command`, or return None if the backend doesn't support it.
+/// Any other spawn failure is an error.
+fn spawn_if_supported<P: Pty>(command: &PtyCommandBuilder) -> Result<Option<P>, failure::Error> {
+    if !P::supports(command) {
+        log::info!("backend doesn't support {:?}", command);
+        return Ok(None);
+    }
+    Ok(Some(P::spawn(command, PtySize::default())?))
+}
+
+/// The program sees the `argv0` it was given
+pub fn argv0<P: Pty>() -> Result<(), failure::Error> {
+    let mut command = sh("tr '\\0' ' ' </proc/$$/cmdline");
+    command.argv0("custom-name");
+    let mut pty = match spawn_if_supported::<P>(&command)? {
+        Some(pty) => pty,
+        None => return Ok(()),
+    };
+    let output = read_to_end(&mut pty)?;
+    ensure!(output.starts_with("custom-name -c "), "argv was {:?}", output);
+    pty.wait()?;
+    Ok(())
+}
+
+/// The umask is set before exec
+pub fn umask<P: Pty>() -> Result<(), failure::Error> {
+    let mut command = sh("umask");
+    command.umask(0o027);
+    let mut pty = match spawn_if_supported::<P>(&command)? {
+        Some(pty) => pty,
+        None => return Ok(()),
+    };
+    let output = read_to_end(&mut pty)?;
+    ensure!(output.trim() == "0027", "umask printed {:?}", output);
+    pty.wait()?;
+    Ok(())
+}
+
+/// A descriptor passed with `inherit_fd` is there under its new number
+pub fn inherited_fd<P: Pty>() -> Result<(), failure::Error> {
+    let mut pipe = filedescriptor::Pipe::new()?;
+    let mut command = sh("echo through the pipe >&5");
+    command.inherit_fd(pipe.write.as_raw_fd(), 5);
+    let spawned = spawn_if_supported::<P>(&command)?;
+    drop(pipe.write);
+    let mut pty = match spawned {
+        Some(pty) => pty,
+        None => return Ok(()),
+    };
+
+    read_to_end(&mut pty)?;
+    let status = pty.wait()?;
+    ensure!(status.success(), "writing to fd 5 failed with {:?}", status);
+    let mut output = String::new();
+    pipe.read.read_to_string(&mut output)?;
+    ensure!(output == "through the pipe\n", "read {:?} from the pipe", output);
+    Ok(())
+}
+
+/// Stdio that isn't on the pty goes where it was asked to
+pub fn stdio_modes<P: Pty>() -> Result<(), failure::Error> {
+    let mut command = sh("echo hidden; echo shown >&2");
+    command.stdout(StdioMode::Null);
+    let mut pty = match spawn_if_supported::<P>(&command)? {
+        Some(pty) => pty,
+        None => return Ok(()),
+    };
+    let output = read_to_end(&mut pty)?;
+    ensure!(output == "shown\r\n", "unexpected output {:?}", output);
+    pty.wait()?;
+    Ok(())
+}
+
+pub fn run_all<P: Pty>() -> Result<(), failure::Error> {
+    echo_via_cat::<P>()?;
+    tty_reports_pts_path::<P>()?;
+    exit_codes::<P>()?;
+    eof_on_exit::<P>()?;
+    resize::<P>()?;
+    signal::<P>()?;
+    run_to_completion::<P>()?;
+    argv0::<P>()?;
+    umask::<P>()?;
+    inherited_fd::<P>()?;
+    stdio_modes::<P>()?;
+    Ok(())
+}
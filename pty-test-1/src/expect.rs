@@ -0,0 +1,305 @@
+use failure::Fail;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Something to wait for in the output
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// An exact sequence of bytes
+    Literal(Vec<u8>),
+    Regex(regex::bytes::Regex),
+    /// Matches when the output is closed, consuming everything left
+    Eof,
+    /// Matches when the timeout expires, leaving the buffer untouched
+    Timeout,
+}
+
+impl Pattern {
+    pub fn literal<S: AsRef<[u8]>>(s: S) -> Self {
+        Pattern::Literal(s.as_ref().to_vec())
+    }
+
+    pub fn regex(re: &str) -> Result<Self, failure::Error> {
+        Ok(Pattern::Regex(regex::bytes::Regex::new(re)?))
+    }
+
+    /// Find the first match in `buffer`, returning (start, end, captures)
+    fn find(&self, buffer: &[u8]) -> Option<(usize, usize, Vec<Option<String>>)> {
+        match self {
+            Pattern::Literal(needle) => {
+                if needle.is_empty() {
+                    return Some((0, 0, vec![]));
+                }
+                buffer
+                    .windows(needle.len())
+                    .position(|w| w == &needle[..])
+                    .map(|start| (start, start + needle.len(), vec![]))
+            }
+            Pattern::Regex(re) => re.captures(buffer).map(|caps| {
+                let m = caps.get(0).unwrap();
+                let captures = caps
+                    .iter()
+                    .skip(1)
+                    .map(|c| c.map(|c| String::from_utf8_lossy(c.as_bytes()).into_owned()))
+                    .collect();
+                (m.start(), m.end(), captures)
+            }),
+            Pattern::Eof | Pattern::Timeout => None,
+        }
+    }
+}
+
+impl From<&str> for Pattern {
+    fn from(s: &str) -> Self {
+        Pattern::literal(s)
+    }
+}
+
+impl From<regex::bytes::Regex> for Pattern {
+    fn from(re: regex::bytes::Regex) -> Self {
+        Pattern::Regex(re)
+    }
+}
+
+/// The result of a successful expect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    /// Index of the pattern that matched, for `expect_any`
+    pub index: usize,
+    /// The output preceding the match
+    pub before: String,
+    /// The matched text, empty for `Eof` and `Timeout`
+    pub matched: String,
+    /// Capture groups of a regex, not including the whole match
+    pub captures: Vec<Option<String>>,
+}
+
+#[derive(Debug, Fail)]
+pub enum ExpectError {
+    #[fail(display = "Timed out waiting for a match, output: {:?}", before)]
+    Timeout { before: String },
+    #[fail(display = "EOF before a match, output: {:?}", before)]
+    Eof { before: String },
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+}
+
+impl From<io::Error> for ExpectError {
+    fn from(e: io::Error) -> Self {
+        ExpectError::Io(e)
+    }
+}
+
+/// Script an interactive program by waiting for its output.
+///
+/// Usually wraps a `UnixMasterPty`.  Output is collected in a buffer that
+/// holds at most `max_buffer` bytes; when it grows beyond that, the oldest
+/// output is discarded.
+pub struct Expect<T> {
+    inner: T,
+    buffer: Vec<u8>,
+    max_buffer: usize,
+    timeout: Option<Duration>,
+    eof: bool,
+}
+
+impl<T> Expect<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            buffer: vec![],
+            max_buffer: 64 * 1024,
+            timeout: Some(Duration::from_secs(30)),
+            eof: false,
+        }
+    }
+
+    /// How long each expect waits for a match, or forever with `None`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_max_buffer(&mut self, max_buffer: usize) {
+        self.max_buffer = max_buffer;
+        self.trim_buffer();
+    }
+
+    /// Output that has been read but not yet consumed by a match
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn trim_buffer(&mut self) {
+        if self.buffer.len() > self.max_buffer {
+            let excess = self.buffer.len() - self.max_buffer;
+            log::debug!("expect buffer full, discarding {} bytes", excess);
+            self.buffer.drain(..excess);
+        }
+    }
+
+    fn buffer_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.buffer).into_owned()
+    }
+
+    /// The earliest match of any pattern in the buffer.
+    /// When two patterns match at the same position, the first one wins.
+    fn find(&mut self, patterns: &[Pattern]) -> Option<Match> {
+        let mut best: Option<(usize, usize, usize, Vec<Option<String>>)> = None;
+        for (index, pattern) in patterns.iter().enumerate() {
+            if let Some((start, end, captures)) = pattern.find(&self.buffer) {
+                if best.as_ref().map_or(true, |b| start < b.1) {
+                    best = Some((index, start, end, captures));
+                }
+            }
+        }
+
+        best.map(|(index, start, end, captures)| {
+            let consumed: Vec<u8> = self.buffer.drain(..end).collect();
+            Match {
+                index,
+                before: String::from_utf8_lossy(&consumed[..start]).into_owned(),
+                matched: String::from_utf8_lossy(&consumed[start..]).into_owned(),
+                captures,
+            }
+        })
+    }
+}
+
+impl<T: AsyncRead + Unpin> Expect<T> {
+    /// Wait for a single pattern
+    pub async fn expect<P: Into<Pattern>>(&mut self, pattern: P) -> Result<Match, ExpectError> {
+        self.expect_any(&[pattern.into()]).await
+    }
+
+    /// Wait until the output is closed, returning everything left
+    pub async fn expect_eof(&mut self) -> Result<Match, ExpectError> {
+        self.expect_any(&[Pattern::Eof]).await
+    }
+
+    /// Wait for the first of several patterns.
+    ///
+    /// EOF and the timeout are errors, unless `Pattern::Eof` or
+    /// `Pattern::Timeout` is one of the patterns.
+    pub async fn expect_any(&mut self, patterns: &[Pattern]) -> Result<Match, ExpectError> {
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            if let Some(m) = self.find(patterns) {
+                return Ok(m);
+            }
+
+            if self.eof {
+                let before = self.buffer_lossy();
+                return match patterns.iter().position(|p| matches!(p, Pattern::Eof)) {
+                    Some(index) => {
+                        self.buffer.clear();
+                        Ok(Match { index, before, matched: String::new(), captures: vec![] })
+                    }
+                    None => Err(ExpectError::Eof { before }),
+                };
+            }
+
+            let read = self.inner.read(&mut chunk);
+            let n = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, read).await {
+                    Ok(r) => r?,
+                    Err(_) => {
+                        let before = self.buffer_lossy();
+                        return match patterns.iter().position(|p| matches!(p, Pattern::Timeout)) {
+                            Some(index) => {
+                                Ok(Match { index, before, matched: String::new(), captures: vec![] })
+                            }
+                            None => Err(ExpectError::Timeout { before }),
+                        };
+                    }
+                },
+                None => read.await?,
+            };
+
+            if n == 0 {
+                self.eof = true;
+            } else {
+                self.buffer.extend_from_slice(&chunk[..n]);
+                self.trim_buffer();
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Expect<T> {
+    pub async fn send<B: AsRef<[u8]>>(&mut self, data: B) -> io::Result<()> {
+        self.inner.write_all(data.as_ref()).await?;
+        self.inner.flush().await
+    }
+
+    /// Send a line, terminated by a carriage return as if Enter was pressed
+    pub async fn send_line(&mut self, line: &str) -> io::Result<()> {
+        self.send(line).await?;
+        self.send("\r").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PtyCommandBuilder;
+    use crate::test::PtySize;
+
+    #[tokio::test]
+    async fn test_matches() -> Result<(), failure::Error> {
+        let mut builder = PtyCommandBuilder::new("sh");
+        builder.arg("-c").arg("printf hel; sleep 0.2; printf 'lo 42 world\\n'; sleep 0.2; echo done");
+        let (master, _child) = builder.spawn_pty(PtySize::default())?;
+        let mut expect = Expect::new(master);
+        expect.set_timeout(Some(Duration::from_secs(10)));
+
+        // both are split across two reads
+        let m = expect.expect("hello").await?;
+        assert_eq!((m.index, m.before.as_str(), m.matched.as_str()), (0, "", "hello"));
+        let m = expect.expect(Pattern::regex(r"(\d+) (x)?wor")?).await?;
+        assert_eq!((m.before.as_str(), m.matched.as_str()), (" ", "42 wor"));
+        assert_eq!(m.captures, vec![Some("42".to_string()), None]);
+        // the rest of that write stays for the next expect
+        assert_eq!(expect.buffer(), b"ld\r\n");
+
+        let m = expect.expect_any(&["nope".into(), "done".into(), Pattern::Eof]).await?;
+        assert_eq!((m.index, m.before.as_str()), (1, "ld\r\n"));
+        let m = expect.expect_eof().await?;
+        assert_eq!((m.index, m.before.as_str()), (0, "\r\n"));
+        assert!(matches!(expect.expect("more").await, Err(ExpectError::Eof { .. })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timeout() -> Result<(), failure::Error> {
+        let (master, _child) = PtyCommandBuilder::new("cat").spawn_pty(PtySize::default())?;
+        let mut expect = Expect::new(master);
+        expect.set_timeout(Some(Duration::from_millis(200)));
+        expect.send_line("partial").await?;
+
+        match expect.expect("never").await {
+            Err(ExpectError::Timeout { before }) => assert!(before.contains("partial"), "{:?}", before),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        // a timeout pattern matches instead, and leaves the buffer alone
+        let m = expect.expect_any(&["never".into(), Pattern::Timeout]).await?;
+        assert_eq!(m.index, 1);
+        assert_eq!(expect.expect("partial").await?.matched, "partial");
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+//! Encode key presses, pastes and mouse events into the bytes a terminal
+//! would send, for writing to the master.
+//!
+//! The sequences follow xterm.  A few of them depend on modes the program
+//! sets, so keep an `InputEncoder` in step with the `vt::Screen` of the
+//! session with `InputEncoder::sync`.
+
+use std::ops::BitOr;
+use crate::vt::Screen;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// F1 to F12
+    F(u8),
+}
+
+impl From<char> for Key {
+    fn from(c: char) -> Self {
+        Key::Char(c)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers { shift: false, alt: false, ctrl: false };
+    pub const SHIFT: Modifiers = Modifiers { shift: true, alt: false, ctrl: false };
+    pub const ALT: Modifiers = Modifiers { shift: false, alt: true, ctrl: false };
+    pub const CTRL: Modifiers = Modifiers { shift: false, alt: false, ctrl: true };
+
+    pub fn is_empty(&self) -> bool {
+        *self == Modifiers::NONE
+    }
+
+    /// The modifier parameter of CSI sequences, as in `ESC [ 1 ; 5 A` for Ctrl-Up
+    fn param(&self) -> u8 {
+        1 + self.shift as u8 + 2 * self.alt as u8 + 4 * self.ctrl as u8
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, other: Modifiers) -> Modifiers {
+        Modifiers {
+            shift: self.shift || other.shift,
+            alt: self.alt || other.alt,
+            ctrl: self.ctrl || other.ctrl,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseAction {
+    Press,
+    Release,
+    /// Motion with the button held
+    Drag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub action: MouseAction,
+    pub button: MouseButton,
+    /// Zero based, like `Screen::cursor`
+    pub row: usize,
+    pub col: usize,
+    pub mods: Modifiers,
+}
+
+impl MouseEvent {
+    pub fn new(action: MouseAction, button: MouseButton, row: usize, col: usize) -> Self {
+        Self { action, button, row, col, mods: Modifiers::NONE }
+    }
+}
+
+/// Turns input events into bytes, following the modes of the terminal
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputEncoder {
+    pub application_cursor: bool,
+    pub bracketed_paste: bool,
+    pub mouse_tracking: bool,
+    pub sgr_mouse: bool,
+}
+
+impl InputEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the input modes the program has set on `screen`
+    pub fn from_screen(screen: &Screen) -> Self {
+        let mut encoder = Self::new();
+        encoder.sync(screen);
+        encoder
+    }
+
+    pub fn sync(&mut self, screen: &Screen) {
+        self.application_cursor = screen.application_cursor();
+        self.bracketed_paste = screen.bracketed_paste();
+        self.mouse_tracking = screen.mouse_tracking();
+        self.sgr_mouse = screen.sgr_mouse();
+    }
+
+    /// The bytes for `key` pressed with `mods`.  Function keys past F12 give nothing.
+    pub fn key(&self, key: Key, mods: Modifiers) -> Vec<u8> {
+        let mut out = vec![];
+        match key {
+            Key::Char(c) => {
+                if mods.alt {
+                    out.push(0x1b);
+                }
+                let c = if mods.shift { c.to_ascii_uppercase() } else { c };
+                match ctrl_char(c) {
+                    Some(b) if mods.ctrl => out.push(b),
+                    _ => {
+                        let mut buf = [0; 4];
+                        out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    }
+                }
+            }
+            Key::Enter => simple(&mut out, mods, b'\r'),
+            Key::Tab if mods.shift => out.extend_from_slice(b"\x1b[Z"),
+            Key::Tab => simple(&mut out, mods, b'\t'),
+            Key::Backspace if mods.ctrl => simple(&mut out, mods, 0x08),
+            Key::Backspace => simple(&mut out, mods, 0x7f),
+            Key::Escape => simple(&mut out, mods, 0x1b),
+            Key::Up => self.cursor(&mut out, mods, b'A'),
+            Key::Down => self.cursor(&mut out, mods, b'B'),
+            Key::Right => self.cursor(&mut out, mods, b'C'),
+            Key::Left => self.cursor(&mut out, mods, b'D'),
+            Key::Home => self.cursor(&mut out, mods, b'H'),
+            Key::End => self.cursor(&mut out, mods, b'F'),
+            Key::Insert => tilde(&mut out, mods, 2),
+            Key::Delete => tilde(&mut out, mods, 3),
+            Key::PageUp => tilde(&mut out, mods, 5),
+            Key::PageDown => tilde(&mut out, mods, 6),
+            Key::F(n @ 1..=4) => ss3(&mut out, mods, b'P' + n - 1),
+            Key::F(n @ 5..=12) => {
+                const CODES: [u8; 8] = [15, 17, 18, 19, 20, 21, 23, 24];
+                tilde(&mut out, mods, CODES[n as usize - 5])
+            }
+            Key::F(n) => log::warn!("No sequence for F{}", n),
+        }
+        out
+    }
+
+    /// The bytes for typing `text`, one key at a time
+    pub fn text(&self, text: &str) -> Vec<u8> {
+        text.chars()
+            .flat_map(|c| match c {
+                '\n' => self.key(Key::Enter, Modifiers::NONE),
+                c => self.key(Key::Char(c), Modifiers::NONE),
+            })
+            .collect()
+    }
+
+    /// The bytes for pasting `text`, wrapped in bracketed paste markers if
+    /// the program asked for them.  An end marker inside `text` is dropped so
+    /// the paste can't end early.
+    pub fn paste(&self, text: &str) -> Vec<u8> {
+        if !self.bracketed_paste {
+            return text.as_bytes().to_vec();
+        }
+        let mut out = b"\x1b[200~".to_vec();
+        out.extend_from_slice(text.replace("\x1b[201~", "").as_bytes());
+        out.extend_from_slice(b"\x1b[201~");
+        out
+    }
+
+    /// The mouse report for `event`, or nothing if the program hasn't turned
+    /// on mouse tracking.  X10 reports can't go past column or row 223.
+    pub fn mouse(&self, event: MouseEvent) -> Vec<u8> {
+        if !self.mouse_tracking {
+            return vec![];
+        }
+
+        let mut code = match event.button {
+            MouseButton::Left => 0,
+            MouseButton::Middle => 1,
+            MouseButton::Right => 2,
+            MouseButton::WheelUp => 64,
+            MouseButton::WheelDown => 65,
+        };
+        if event.action == MouseAction::Drag {
+            code += 32;
+        }
+        code += 4 * event.mods.shift as usize + 8 * event.mods.alt as usize + 16 * event.mods.ctrl as usize;
+        let (x, y) = (event.col + 1, event.row + 1);
+
+        if self.sgr_mouse {
+            let end = if event.action == MouseAction::Release { 'm' } else { 'M' };
+            return format!("\x1b[<{};{};{}{}", code, x, y, end).into_bytes();
+        }
+
+        // X10 doesn't say which button was released
+        if event.action == MouseAction::Release {
+            code = (code & !3) | 3;
+        }
+        if x > 223 || y > 223 {
+            return vec![];
+        }
+        vec![0x1b, b'[', b'M', 32 + code as u8, 32 + x as u8, 32 + y as u8]
+    }
+
+    fn cursor(&self, out: &mut Vec<u8>, mods: Modifiers, code: u8) {
+        if mods.is_empty() && self.application_cursor {
+            out.extend_from_slice(&[0x1b, b'O', code]);
+        } else if mods.is_empty() {
+            out.extend_from_slice(&[0x1b, b'[', code]);
+        } else {
+            out.extend_from_slice(format!("\x1b[1;{}", mods.param()).as_bytes());
+            out.push(code);
+        }
+    }
+}
+
+/// The control character for Ctrl-`c`, if there is one
+fn ctrl_char(c: char) -> Option<u8> {
+    match c {
+        'a'..='z' | 'A'..='Z' => Some(c.to_ascii_uppercase() as u8 & 0x1f),
+        '@' | ' ' | '2' => Some(0),
+        '[' | '3' => Some(0x1b),
+        '\\' | '4' => Some(0x1c),
+        ']' | '5' => Some(0x1d),
+        '^' | '6' => Some(0x1e),
+        '_' | '7' | '/' => Some(0x1f),
+        '?' | '8' => Some(0x7f),
+        _ => None,
+    }
+}
+
+/// A key that sends a single byte, with Alt as an ESC prefix
+fn simple(out: &mut Vec<u8>, mods: Modifiers, b: u8) {
+    if mods.alt {
+        out.push(0x1b);
+    }
+    out.push(b);
+}
+
+fn ss3(out: &mut Vec<u8>, mods: Modifiers, code: u8) {
+    if mods.is_empty() {
+        out.extend_from_slice(&[0x1b, b'O', code]);
+    } else {
+        out.extend_from_slice(format!("\x1b[1;{}", mods.param()).as_bytes());
+        out.push(code);
+    }
+}
+
+fn tilde(out: &mut Vec<u8>, mods: Modifiers, n: u8) {
+    if mods.is_empty() {
+        out.extend_from_slice(format!("\x1b[{}~", n).as_bytes());
+    } else {
+        out.extend_from_slice(format!("\x1b[{};{}~", n, mods.param()).as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let mut encoder = InputEncoder::new();
+        assert_eq!(encoder.key(Key::Char('c'), Modifiers::CTRL), b"\x03");
+        assert_eq!(encoder.key(Key::Char('x'), Modifiers::ALT), b"\x1bx");
+        assert_eq!(encoder.key('é'.into(), Modifiers::NONE), "é".as_bytes());
+        assert_eq!(encoder.key(Key::Tab, Modifiers::SHIFT), b"\x1b[Z");
+        assert_eq!(encoder.key(Key::Up, Modifiers::NONE), b"\x1b[A");
+        assert_eq!(encoder.key(Key::Right, Modifiers::CTRL | Modifiers::SHIFT), b"\x1b[1;6C");
+        assert_eq!(encoder.key(Key::F(1), Modifiers::NONE), b"\x1bOP");
+        assert_eq!(encoder.key(Key::F(12), Modifiers::ALT), b"\x1b[24;3~");
+        assert_eq!(encoder.key(Key::PageDown, Modifiers::NONE), b"\x1b[6~");
+        assert_eq!(encoder.text("q\n"), b"q\r");
+
+        let mut screen = Screen::new(5, 10);
+        screen.feed(b"\x1b[?1h\x1b[?2004h\x1b[?1000h\x1b[?1006h");
+        encoder.sync(&screen);
+        assert_eq!(encoder.key(Key::Up, Modifiers::NONE), b"\x1bOA");
+        assert_eq!(encoder.key(Key::Home, Modifiers::NONE), b"\x1bOH");
+        assert_eq!(encoder.paste("hi"), b"\x1b[200~hi\x1b[201~");
+    }
+
+    #[test]
+    fn test_mouse() {
+        let mut encoder = InputEncoder::new();
+        let press = MouseEvent::new(MouseAction::Press, MouseButton::Left, 2, 4);
+        let release = MouseEvent::new(MouseAction::Release, MouseButton::Right, 2, 4);
+        assert_eq!(encoder.mouse(press), b"");
+        encoder.mouse_tracking = true;
+        assert_eq!(encoder.mouse(press), b"\x1b[M %#");
+        assert_eq!(encoder.mouse(release), b"\x1b[M#%#");
+        encoder.sgr_mouse = true;
+        assert_eq!(encoder.mouse(press), b"\x1b[<0;5;3M");
+        assert_eq!(encoder.mouse(release), b"\x1b[<2;5;3m");
+    }
+}
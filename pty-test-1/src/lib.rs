@@ -1,2 +1,29 @@
 pub mod test;
 pub use test::*;
+pub mod command;
+pub use command::*;
+pub mod termios;
+pub use termios::*;
+pub mod child;
+pub use child::*;
+pub mod expect;
+pub use expect::*;
+pub mod vt;
+pub use vt::*;
+pub mod asciicast;
+pub use asciicast::*;
+pub mod mux;
+pub use mux::*;
+pub mod backend;
+pub use backend::*;
+pub mod conformance;
+pub mod openpt;
+pub use openpt::*;
+pub mod utf8;
+pub use utf8::*;
+pub mod keys;
+pub use keys::*;
+pub mod transcript;
+pub use transcript::*;
+pub mod queue;
+pub use queue::*;
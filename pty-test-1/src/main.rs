@@ -19,12 +19,7 @@ enum Message {
 }
 
 async fn test1() -> Result<(), failure::Error> {
-    use pty_test::test::*;
-    let (mut master, mut slave) = openpty(PtySize::default())?;
-
-    // these need to stick around for some reason
-    //let mut reader1 = PtyFd::from_fd(master.fd.try_clone()?)?;
-    //let mut reader2 = PtyFd::from_fd(slave.fd.try_clone()?)?;
+    use pty_test::*;
 
     let mut argv = std::env::args().collect::<Vec<_>>();
     let mut part1 = argv.split_off(1);
@@ -35,137 +30,60 @@ async fn test1() -> Result<(), failure::Error> {
     let cmd = part1.get(0).unwrap();
     log::info!("{:?}", (&cmd,&args));
 
-    let mut command = tokio::process::Command::new(cmd);
-    command.kill_on_drop(true);
+    // the program gets the pty as stdin and controlling terminal,
+    // but stdout and stderr are socketpairs so we can tell them apart
+    let mut command = PtyCommandBuilder::new(cmd);
     command.args(args);
+    command.stdout(StdioMode::SocketPair);
+    command.stderr(StdioMode::SocketPair);
 
-    let (mut stdout_a, stdout_b) = socketpair::tokio_socketpair_stream().await?;
-    let (stderr_a, stderr_b) = socketpair::tokio_socketpair_stream().await?;
-    let (stdin_a, stdin_b) = socketpair::tokio_socketpair_stream().await?;
-    //let (stdin_a, stdin_b) = tokio::io::duplex(100);
-    //let (stdin_a, stdin_b) = std::os::unix::net::UnixStream::pair()?;
-    //let (stdin_a, stdin_b) = os_pipe::pipe()?;
-    //stdin_a.set_nonblocking(false);
-    //stdin_b.set_nonblocking(false);
-    //let c = stdout_a.into_std().try_clone()?;
-
-    //let stdin = slave.fd.as_stdio()?;
-    //println!("x{:?}", stdin);
-    let stdin = unsafe { std::process::Stdio::from_raw_fd(stdin_a.as_raw_fd()) };
-    let stdin_a = unsafe { std::process::Stdio::from_raw_fd(stdin_a.as_raw_fd()) };
-    command.stdin(stdin);
-    let stdout = unsafe { std::process::Stdio::from_raw_fd(stdout_a.as_raw_fd()) };
-    command.stdout(stdout);
-    let stderr = unsafe { std::process::Stdio::from_raw_fd(stderr_a.as_raw_fd()) };
-    command.stderr(stderr);
-
-    //let master_stdout = unsafe { std::process::Stdio::from_raw_fd(master.fd.as_raw_fd()) };
-
-    //let dup_stdin = os_pipe::dup_stdin()?;
-    //command.stdin(dup_stdin);
-    //let other = slave.fd.try_clone()?;
-
-    command.stdin(slave.fd.as_stdio()?);
-    //command.stdin(master.fd.as_stdio()?);
-    //command.stdin(Stdio::null());
-    //command.stdin(Stdio::piped());
-    //command.stdin(Stdio::inherit());
-    //let mut reader = master.try_clone_reader()?;
-    //let reader = slave.fd.try_clone()?.as_stdio()?;
-    //command.stdin(reader);
-    //let status = child.wait().await?;
-    //let status = child.wait().await?;
-    //command.stdout(slave.fd.as_stdio()?);
-    //command.stderr(slave.fd.as_stdio()?);
-
-    let mut child = slave.spawn_command(command)?;
-    drop(slave);
-    //drop(master);
-
+    let (mut master, mut child) = command.spawn_pty(PtySize::default())?;
     println!("{:?}", child);
-    //println!("{:?}", reader2);
-    //let stdin = child.stdin.take().unwrap();
-    //let stdout = child.stdout.take().unwrap();
-    //let stderr = child.stderr.take().unwrap();
-
-    //let r = tokio::io::AsyncReadExt::read(&mut master.fd, &mut buffer[..]).await;
-
-    // This writes synchronously, and it works
-    let xy = std::io::Write::write(&mut master.fd, b"asdf\n");
-    println!("xy {:?}", (xy));
-    std::io::Write::flush(&mut master.fd);
-
-    // This writes async, and it seems to work, but it's just reading back what we sent
-    for i in 0..10 {
-        let xx = tokio::io::AsyncWriteExt::write(&mut master.fd, b"xxxx\n").await;
-        println!("xx {:?}", (xx));
-    }
-    //std::io::Write::flush(&mut master.fd);
-    std::io::Write::flush(&mut master.fd);
-
-     //we are able to read like this, but it's sync
-    let mut buffer = [0;10];
-    for i in 0..3 {
-        if let Ok(r) = std::io::Read::read(&mut master.fd, &mut buffer[..]) {
-            println!("r{:?}", (r, &buffer[..r]));
-        }
-    }
-    
-     //when we try to read async, we don't get anything
-    if let Ok(r) = tokio::io::AsyncReadExt::read(&mut master.fd, &mut buffer[..]).await {
-        println!("async read {:?}", (r, &buffer[..r]));
-    }
-    
-    //let mut framed_stdin = codec::FramedWrite::new(other.as_stdio()?, codec::BytesCodec::new());
-    let mut framed_stdin = codec::FramedWrite::new(stdin_b, codec::BytesCodec::new());
-    //let mut framed_stdout = codec::FramedRead::new(slave.fd, codec::BytesCodec::new());
-    let mut framed_stdout = codec::FramedRead::new(stdout_b, codec::BytesCodec::new());
-    //let mut framed_stdout = codec::FramedRead::new(master_stdout, codec::BytesCodec::new());
-    let mut framed_stderr = codec::FramedRead::new(stderr_b, codec::BytesCodec::new());
-
-    framed_stdin.send(bytes::Bytes::from("asdf\n\n")).await;
 
-    loop {
+    // input goes through the master; the echo comes back on the master too,
+    // not on stdout
+    master.write_all_timeout(b"asdf\n\n", std::time::Duration::from_secs(5)).await?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let mut framed_stdout = codec::FramedRead::new(stdout, codec::BytesCodec::new());
+    let mut framed_stderr = codec::FramedRead::new(stderr, codec::BytesCodec::new());
+    let mut transcript = TranscriptWriter::new(std::io::stdout());
+    let id = child.id().to_string();
+
+    // drain both streams to EOF before reaping, so that nothing written
+    // just before the exit is lost
+    let mut stdout_done = false;
+    let mut stderr_done = false;
+    while !(stdout_done && stderr_done) {
         tokio::select! {
-            x = framed_stdout.try_next() => {
+            x = framed_stdout.try_next(), if !stdout_done => {
                 match x {
-                    // break on EOF
-                    Ok(None) => break,
-                    Ok(Some(v)) => print!("{:?}", v),
-                    Err(e) => print!("{:?}", e),
-                    _ => ()
-
+                    Ok(None) => stdout_done = true,
+                    Ok(Some(v)) => transcript.write(&id, Stream::Stdout, &v)?,
+                    Err(e) => {
+                        log::error!("stdout: {:?}", e);
+                        stdout_done = true;
+                    }
                 }
             }
 
-            x = framed_stderr.try_next() => {
+            x = framed_stderr.try_next(), if !stderr_done => {
                 match x {
-                    Ok(None) => break,
-                    Ok(Some(v)) => print!("ERR: {:?}", v),
-                    Err(e) => print!("ERR: {:?}", e),
-                    _ => ()
-
-                }
-            }
-            // check for wait
-            r = child.wait() => {
-                if let Ok(status) = r {
-                    log::info!("child status: {:?}", (status.success(), status.code(), status));
+                    Ok(None) => stderr_done = true,
+                    Ok(Some(v)) => transcript.write(&id, Stream::Stderr, &v)?,
+                    Err(e) => {
+                        log::error!("stderr: {:?}", e);
+                        stderr_done = true;
+                    }
                 }
             }
         }
     }
+    transcript.finish_all()?;
 
-    // make sure we get anything that remains
-    if let Ok(Some(x)) = framed_stdout.try_next().await {
-        println!("{:?}", x);
-    }
-    if let Ok(Some(x)) = framed_stderr.try_next().await {
-        println!("{:?}", x);
-    }
-
-    // the last thing we do is wait for the child to exit
-    child.wait().await;
+    let status = child.wait().await?;
+    log::info!("child status: {:?}", (status.success(), status.code(), status));
 
     Ok(())
 }
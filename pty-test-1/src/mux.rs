@@ -0,0 +1,321 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+/// What to do when a subscriber doesn't keep up with the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Wait for the subscriber.  This slows down reading from the pty,
+    /// and so every other subscriber.
+    Block,
+    /// Drop the output the subscriber has no room for
+    Drop,
+    /// Disconnect the subscriber
+    Disconnect,
+}
+
+struct Subscription {
+    tx: mpsc::Sender<Bytes>,
+    policy: LagPolicy,
+    dropped: Arc<AtomicUsize>,
+}
+
+struct Shared {
+    subscribers: Vec<Subscription>,
+    scrollback: VecDeque<u8>,
+    scrollback_limit: usize,
+    closed: bool,
+}
+
+impl Shared {
+    fn push_scrollback(&mut self, data: &[u8]) {
+        self.scrollback.extend(data);
+        let excess = self.scrollback.len().saturating_sub(self.scrollback_limit);
+        self.scrollback.drain(..excess);
+    }
+}
+
+/// Shares one pty master between many readers and writers.
+///
+/// Output is copied to every subscriber, and the last `scrollback` bytes
+/// are kept so that late subscribers can catch up.  Input from all the
+/// `InputHandle`s is merged and written to the master in the order it arrives.
+///
+/// Dropping the multiplexer stops it; subscribers then see the end of the stream.
+pub struct Multiplexer {
+    shared: Arc<Mutex<Shared>>,
+    input: mpsc::Sender<Bytes>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Multiplexer {
+    /// Start reading from and writing to `pty`, must be called from within a tokio runtime
+    pub fn new<T>(pty: T, scrollback: usize) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(pty);
+        let shared = Arc::new(Mutex::new(Shared {
+            subscribers: vec![],
+            scrollback: VecDeque::new(),
+            scrollback_limit: scrollback,
+            closed: false,
+        }));
+
+        let (input, mut input_rx) = mpsc::channel::<Bytes>(64);
+        let writer = tokio::spawn(async move {
+            while let Some(data) = input_rx.recv().await {
+                if let Err(e) = writer.write_all(&data).await {
+                    log::error!("Failed to write to pty: {:?}", e);
+                    break;
+                }
+            }
+        });
+
+        let reader_shared = shared.clone();
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![0u8; 8192];
+            loop {
+                let n = match reader.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => {
+                        log::error!("Failed to read from pty: {:?}", e);
+                        break;
+                    }
+                };
+                let chunk = Bytes::copy_from_slice(&buf[..n]);
+
+                let blocking = {
+                    let mut shared = reader_shared.lock().unwrap();
+                    shared.push_scrollback(&chunk);
+
+                    let mut blocking = vec![];
+                    shared.subscribers.retain(|sub| match sub.policy {
+                        LagPolicy::Block => {
+                            blocking.push(sub.tx.clone());
+                            !sub.tx.is_closed()
+                        }
+                        LagPolicy::Drop => match sub.tx.try_send(chunk.clone()) {
+                            Ok(()) => true,
+                            Err(TrySendError::Full(_)) => {
+                                sub.dropped.fetch_add(n, Ordering::Relaxed);
+                                true
+                            }
+                            Err(TrySendError::Closed(_)) => false,
+                        },
+                        LagPolicy::Disconnect => sub.tx.try_send(chunk.clone()).is_ok(),
+                    });
+                    blocking
+                };
+
+                // don't hold the lock while waiting on slow subscribers
+                for tx in blocking {
+                    let _ = tx.send(chunk.clone()).await;
+                }
+            }
+
+            let mut shared = reader_shared.lock().unwrap();
+            shared.closed = true;
+            shared.subscribers.clear();
+        });
+
+        Self { shared, input, reader, writer }
+    }
+
+    /// Subscribe to the output.
+    ///
+    /// `capacity` is the number of chunks that can be queued for the
+    /// subscriber before `policy` applies.  With `replay`, the subscriber
+    /// first receives the scrollback as a single chunk.
+    pub fn subscribe(&self, capacity: usize, policy: LagPolicy, replay: bool) -> Subscriber {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        // subscribe under the lock, so that nothing is missed or repeated
+        // between the scrollback and the live output
+        let mut shared = self.shared.lock().unwrap();
+        if replay && !shared.scrollback.is_empty() {
+            let scrollback: Vec<u8> = shared.scrollback.iter().copied().collect();
+            let _ = tx.try_send(Bytes::from(scrollback));
+        }
+        if !shared.closed {
+            shared.subscribers.push(Subscription { tx, policy, dropped: dropped.clone() });
+        }
+
+        Subscriber { rx, dropped }
+    }
+
+    /// A handle for writing to the pty, which can be cloned
+    pub fn input(&self) -> InputHandle {
+        InputHandle { tx: self.input.clone() }
+    }
+
+    pub fn scrollback(&self) -> Vec<u8> {
+        self.shared.lock().unwrap().scrollback.iter().copied().collect()
+    }
+
+    /// The pty has reached EOF
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().closed
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.shared.lock().unwrap().subscribers.len()
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+/// Output from a `Multiplexer`
+pub struct Subscriber {
+    rx: mpsc::Receiver<Bytes>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Subscriber {
+    /// The next chunk of output, or None once the pty is closed
+    /// or the subscriber has been disconnected
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.rx.recv().await
+    }
+
+    /// Number of bytes dropped because of `LagPolicy::Drop`
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for Subscriber {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Writes to a `Multiplexer`
+#[derive(Debug, Clone)]
+pub struct InputHandle {
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl InputHandle {
+    pub async fn send<B: Into<Bytes>>(&self, data: B) -> Result<(), failure::Error> {
+        self.tx
+            .send(data.into())
+            .await
+            .map_err(|_| failure::err_msg("Multiplexer has stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PtyCommandBuilder;
+    use crate::test::PtySize;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    async fn read_until(sub: &mut Subscriber, needle: &str) -> String {
+        let mut out = vec![];
+        while !String::from_utf8_lossy(&out).contains(needle) {
+            let chunk = timeout(TIMEOUT, sub.recv()).await.unwrap();
+            out.extend_from_slice(&chunk.unwrap_or_else(|| panic!("end before {:?}", needle)));
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    async fn read_to_end(mut sub: Subscriber) -> Vec<u8> {
+        let mut out = vec![];
+        while let Some(chunk) = timeout(TIMEOUT, sub.recv()).await.unwrap() {
+            out.extend_from_slice(&chunk);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_and_input() -> Result<(), failure::Error> {
+        let (master, _child) = PtyCommandBuilder::new("cat").spawn_pty(PtySize::default())?;
+        let mux = Multiplexer::new(master, 1024);
+        let mut subs = (0..3).map(|_| mux.subscribe(16, LagPolicy::Block, false)).collect::<Vec<_>>();
+        let gone = mux.subscribe(16, LagPolicy::Block, false);
+        drop(gone);
+
+        // two writers
+        let input = mux.input();
+        input.send(&b"hello\n"[..]).await?;
+        for sub in &mut subs {
+            read_until(sub, "hello\r\nhello\r\n").await;
+        }
+        mux.input().send(&b"world\n"[..]).await?;
+        for sub in &mut subs {
+            read_until(sub, "world\r\nworld\r\n").await;
+        }
+        // the dropped one is noticed on the next chunk
+        assert_eq!(mux.subscriber_count(), 3);
+
+        let late = mux.subscribe(16, LagPolicy::Block, true);
+        input.send(&b"\x04"[..]).await?;
+        let replayed = String::from_utf8(read_to_end(late).await)?;
+        assert!(replayed.starts_with("hello\r\nhello\r\n"), "{:?}", replayed);
+
+        // cat exiting ends every stream
+        for sub in subs {
+            read_to_end(sub).await;
+        }
+        assert!(mux.is_closed());
+        assert_eq!(mux.subscriber_count(), 0);
+        assert!(mux.subscribe(16, LagPolicy::Block, false).recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lag_policies() -> Result<(), failure::Error> {
+        let expected = (1..=20000).map(|n| format!("{}\r\n", n)).collect::<String>();
+        let (master, _child) = PtyCommandBuilder::new("seq").arg("1").arg("20000").spawn_pty(PtySize::default())?;
+        let mux = Multiplexer::new(master, 0);
+
+        // nothing runs until the first await, so no output is missed
+        let fast = tokio::spawn(read_to_end(mux.subscribe(1, LagPolicy::Block, false)));
+        let mut slow_block = mux.subscribe(1, LagPolicy::Block, false);
+        let slow = tokio::spawn(async move {
+            let mut out = vec![];
+            while let Some(chunk) = slow_block.recv().await {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                out.extend_from_slice(&chunk);
+            }
+            out
+        });
+        let dropping = mux.subscribe(1, LagPolicy::Drop, false);
+        let disconnected = mux.subscribe(1, LagPolicy::Disconnect, false);
+
+        // a blocking subscriber gets everything, however slow
+        assert_eq!(String::from_utf8(fast.await?)?, expected);
+        assert_eq!(String::from_utf8(slow.await?)?, expected);
+
+        // the others weren't read at all until now
+        let dropped = dropping.dropped();
+        let kept = read_to_end(dropping).await;
+        assert!(dropped > 0);
+        assert_eq!(kept.len() + dropped, expected.len());
+        assert!(read_to_end(disconnected).await.len() < expected.len());
+        Ok(())
+    }
+}
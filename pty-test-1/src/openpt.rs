@@ -0,0 +1,162 @@
+//! Allocate ptys with posix_openpt(3) instead of openpty(3).
+//!
+//! Only the master is opened.  The slave is found by its path, so it can be
+//! opened later: by us, by the child after it has called setsid, or by a
+//! program that is given the path and opens the tty itself.
+
+use filedescriptor::FileDescriptor;
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use crate::test::{cloexec, resize_fd, spawn_with_tty, PreExec, PtyFd, PtySize, Tty, UnixMasterPty, UnixSlavePty};
+
+/// Open a new pty master with posix_openpt(3), with the slave unlocked and
+/// ready to be opened.  The master is blocking, use `openpt` for tokio.
+pub fn openpt_fd(size: PtySize) -> Result<FileDescriptor, failure::Error> {
+    let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+    if fd == -1 {
+        log::error!("Failed to posix_openpt: {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
+    }
+    let master = unsafe { FileDescriptor::from_raw_fd(fd) };
+    cloexec(fd)?;
+
+    if unsafe { libc::grantpt(fd) } != 0 {
+        log::error!("Failed to grantpt: {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
+    }
+    if unsafe { libc::unlockpt(fd) } != 0 {
+        log::error!("Failed to unlockpt: {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
+    }
+
+    resize_fd(fd, size)?;
+    Ok(master)
+}
+
+/// Open a new pty master with posix_openpt(3).
+/// Like `openpty`, the master is registered with the tokio reactor, so this
+/// must be called from within a tokio runtime.
+pub fn openpt(size: PtySize) -> Result<UnixMasterPty, failure::Error> {
+    Ok(UnixMasterPty {
+        fd: PtyFd::from_fd_async(openpt_fd(size)?)?,
+    })
+}
+
+/// The path of the slave of the master `fd`
+#[cfg(target_os = "linux")]
+pub fn ptsname_fd(fd: RawFd) -> Result<PathBuf, failure::Error> {
+    let mut buf = [0 as libc::c_char; 128];
+    let result = unsafe { libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result).into());
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Ok(OsStr::from_bytes(name.to_bytes()).into())
+}
+
+/// The path of the slave of the master `fd`
+#[cfg(not(target_os = "linux"))]
+pub fn ptsname_fd(fd: RawFd) -> Result<PathBuf, failure::Error> {
+    // ptsname(3) returns a static buffer
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _guard = LOCK.lock().unwrap();
+    let name = unsafe { libc::ptsname(fd) };
+    if name.is_null() {
+        return Err(io::Error::last_os_error().into());
+    }
+    let name = unsafe { CStr::from_ptr(name) };
+    Ok(OsStr::from_bytes(name.to_bytes()).into())
+}
+
+/// The number of the slave of the master `fd`, the 3 in /dev/pts/3
+pub fn pts_number_fd(fd: RawFd) -> Result<u32, failure::Error> {
+    let path = ptsname_fd(fd)?;
+    let name = path.to_string_lossy();
+    let digits = name.len() - name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    name[name.len() - digits..]
+        .parse()
+        .map_err(|_| failure::format_err!("No pts number in {:?}", path))
+}
+
+/// Open a slave by its path.  It's opened with O_NOCTTY, so that it doesn't
+/// become our controlling terminal if we happen to be a session leader without one.
+pub fn open_slave_path(path: &Path) -> Result<UnixSlavePty, failure::Error> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(UnixSlavePty {
+        fd: PtyFd::from_raw_fd(fd)?,
+    })
+}
+
+impl UnixMasterPty {
+    /// The path of the slave device, such as /dev/pts/3
+    pub fn ptsname(&self) -> Result<PathBuf, failure::Error> {
+        ptsname_fd(self.fd.as_raw_fd())
+    }
+
+    pub fn pts_number(&self) -> Result<u32, failure::Error> {
+        pts_number_fd(self.fd.as_raw_fd())
+    }
+
+    /// Open the slave by its path
+    pub fn open_slave(&self) -> Result<UnixSlavePty, failure::Error> {
+        open_slave_path(&self.ptsname()?)
+    }
+
+    /// Spawn `cmd` without opening the slave in this process.  The child
+    /// opens it by path after setsid, makes it its controlling terminal and
+    /// puts it on each of stdin, stdout and stderr marked true in `stdio`.
+    /// Those streams can be left as `Stdio::inherit()` in `cmd`, they are replaced.
+    pub fn spawn_command_lazy(
+        &self,
+        cmd: tokio::process::Command,
+        stdio: [bool; 3],
+        pre_exec: PreExec,
+    ) -> Result<tokio::process::Child, failure::Error> {
+        let path = CString::new(self.ptsname()?.as_os_str().as_bytes())?;
+        spawn_with_tty(cmd, Tty::Path(path, stdio), pre_exec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PtyCommandBuilder;
+    use std::io::Write;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_openpt() -> Result<(), failure::Error> {
+        let size = PtySize { rows: 9, cols: 41, ..PtySize::default() };
+        let mut master = openpt(size)?;
+        let path = master.ptsname()?;
+        assert_eq!(path.to_str().unwrap(), format!("/dev/pts/{}", master.pts_number()?));
+
+        let mut slave = open_slave_path(&path)?;
+        assert_eq!(crate::test::get_size_fd(slave.fd.as_raw_fd())?, size);
+        slave.fd.write_all(b"from the slave\n")?;
+        let mut buf = [0; 64];
+        let n = master.read(&mut buf).await?;
+        assert_eq!(&buf[..n], b"from the slave\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lazy_spawn() -> Result<(), failure::Error> {
+        let size = PtySize { rows: 9, cols: 41, ..PtySize::default() };
+        let mut command = PtyCommandBuilder::new("sh");
+        command.arg("-c").arg("tty; stty size; tty -s </dev/tty && echo ctty");
+        let (mut master, mut child) = command.spawn_pty_lazy(size)?;
+        let path = master.ptsname()?;
+        let completion = child.run_to_completion(&mut master).await?;
+        assert_eq!(completion.output_lossy(), format!("{}\r\n9 41\r\nctty\r\n", path.display()));
+        assert!(completion.status.success());
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+//! Writing to a child that may stop reading.
+//!
+//! The pty only buffers a few kilobytes of input; once that is full, writes
+//! to the master wait until the child reads.  `write_all_timeout` gives up at
+//! a deadline, and `WriteQueue` decouples senders from the master with a
+//! bounded queue.  `input_pending_fd` tells how much the child hasn't read yet.
+
+use bytes::Bytes;
+use failure::Fail;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::test::{UnixMasterPty, UnixSlavePty};
+
+#[derive(Debug, Fail)]
+pub enum WriteError {
+    #[fail(display = "Timed out after writing {} of {} bytes", written, total)]
+    Timeout { written: usize, total: usize },
+    #[fail(display = "Write queue is full")]
+    Full,
+    #[fail(display = "Write queue has stopped")]
+    Closed,
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+}
+
+impl From<io::Error> for WriteError {
+    fn from(e: io::Error) -> Self {
+        WriteError::Io(e)
+    }
+}
+
+/// Like `write_all`, but give up if it's not done by `timeout` from now.
+/// On timeout, the bytes before `written` have been written.
+pub async fn write_all_timeout<W>(writer: &mut W, data: &[u8], timeout: Duration) -> Result<(), WriteError>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let deadline = Instant::now() + timeout;
+    let mut written = 0;
+    while written < data.len() {
+        match tokio::time::timeout_at(deadline, writer.write(&data[written..])).await {
+            Err(_) => return Err(WriteError::Timeout { written, total: data.len() }),
+            Ok(Ok(0)) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+            Ok(Ok(n)) => written += n,
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Number of bytes that can be read from `fd` without blocking, FIONREAD.
+/// On a pty slave that is the input the child hasn't read yet, in
+/// canonical mode only up to the last complete line.
+pub fn input_pending_fd(fd: RawFd) -> Result<usize, failure::Error> {
+    let mut n: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::FIONREAD as _, &mut n as *mut _) } != 0 {
+        log::error!("Failed to ioctl(FIONREAD): {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(n as usize)
+}
+
+/// Number of bytes written to the terminal `fd` that haven't been sent yet, TIOCOUTQ
+pub fn output_pending_fd(fd: RawFd) -> Result<usize, failure::Error> {
+    let mut n: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::TIOCOUTQ as _, &mut n as *mut _) } != 0 {
+        log::error!("Failed to ioctl(TIOCOUTQ): {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(n as usize)
+}
+
+impl UnixMasterPty {
+    pub async fn write_all_timeout(&mut self, data: &[u8], timeout: Duration) -> Result<(), WriteError> {
+        write_all_timeout(self, data, timeout).await
+    }
+
+    /// Output from the child that hasn't been read from the master yet
+    pub fn output_pending(&self) -> Result<usize, failure::Error> {
+        input_pending_fd(self.fd.as_raw_fd())
+    }
+}
+
+impl UnixSlavePty {
+    /// Input written to the master that the child hasn't read from the slave yet
+    pub fn input_pending(&self) -> Result<usize, failure::Error> {
+        input_pending_fd(self.fd.as_raw_fd())
+    }
+}
+
+/// What `WriteQueue::send` does when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait for room
+    Block,
+    /// Fail with `WriteError::Full`
+    Error,
+    /// Discard the data, counting it in `dropped`
+    Drop,
+}
+
+/// A bounded queue of writes to a pty master, or anything else, written
+/// in order by a background task.
+///
+/// If a write fails or times out, the task stops; the error is returned by
+/// the next `send` or by `close`, and later sends fail with `Closed`.
+pub struct WriteQueue {
+    tx: mpsc::Sender<Bytes>,
+    capacity: usize,
+    policy: QueuePolicy,
+    dropped: Arc<AtomicUsize>,
+    error: Arc<Mutex<Option<WriteError>>>,
+    writer: JoinHandle<()>,
+}
+
+impl WriteQueue {
+    /// Start writing to `writer`.  `capacity` is the number of chunks that
+    /// can wait, and each write must finish within `timeout`, if given.
+    /// Must be called from within a tokio runtime.
+    pub fn new<W>(mut writer: W, capacity: usize, policy: QueuePolicy, timeout: Option<Duration>) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let capacity = capacity.max(1);
+        let (tx, mut rx) = mpsc::channel::<Bytes>(capacity);
+        let error = Arc::new(Mutex::new(None));
+
+        let writer_error = error.clone();
+        let writer = tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                let result = match timeout {
+                    Some(timeout) => write_all_timeout(&mut writer, &data, timeout).await,
+                    None => writer.write_all(&data).await.map_err(WriteError::from),
+                };
+                if let Err(e) = result {
+                    log::error!("Failed to write queued input: {:?}", e);
+                    *writer_error.lock().unwrap() = Some(e);
+                    break;
+                }
+            }
+        });
+
+        Self { tx, capacity, policy, dropped: Arc::new(AtomicUsize::new(0)), error, writer }
+    }
+
+    pub async fn send<B: Into<Bytes>>(&self, data: B) -> Result<(), WriteError> {
+        let data = data.into();
+        let n = data.len();
+        let result = match self.policy {
+            QueuePolicy::Block => self.tx.send(data).await.map_err(|_| WriteError::Closed),
+            QueuePolicy::Error | QueuePolicy::Drop => match self.tx.try_send(data) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) if self.policy == QueuePolicy::Drop => {
+                    self.dropped.fetch_add(n, Ordering::Relaxed);
+                    Ok(())
+                }
+                Err(TrySendError::Full(_)) => Err(WriteError::Full),
+                Err(TrySendError::Closed(_)) => Err(WriteError::Closed),
+            },
+        };
+        match result {
+            Err(WriteError::Closed) => Err(self.take_error().unwrap_or(WriteError::Closed)),
+            result => result,
+        }
+    }
+
+    /// Number of chunks waiting to be written, not counting the one being written
+    pub fn queued(&self) -> usize {
+        self.capacity - self.tx.capacity()
+    }
+
+    /// Number of bytes discarded because of `QueuePolicy::Drop`
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wait for everything queued to be written
+    pub async fn close(self) -> Result<(), WriteError> {
+        let WriteQueue { tx, error, writer, .. } = self;
+        drop(tx);
+        if let Err(e) = writer.await {
+            return Err(io::Error::new(io::ErrorKind::Other, e).into());
+        }
+        let error = error.lock().unwrap().take();
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn take_error(&self) -> Option<WriteError> {
+        self.error.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PtyCommandBuilder;
+    use crate::test::{openpty, PtySize};
+
+    #[tokio::test]
+    async fn test_child_not_reading() -> Result<(), failure::Error> {
+        let mut builder = PtyCommandBuilder::new("sleep");
+        builder.arg("10").kill_on_drop(true);
+        // keep the slave, it is what tells how much the child hasn't read
+        let (mut master, slave) = openpty(PtySize::default())?;
+        let (_child, _stdio) = builder.spawn(&slave)?;
+
+        let data = b"0123456789abcde\n".repeat(4096);
+        match master.write_all_timeout(&data, Duration::from_millis(200)).await {
+            Err(WriteError::Timeout { written, total }) => assert!(written < total),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(slave.input_pending()? > 0);
+
+        let queue = WriteQueue::new(master, 1, QueuePolicy::Error, Some(Duration::from_millis(200)));
+        queue.send(data.clone()).await?;
+        tokio::task::yield_now().await;
+        let mut full = false;
+        for _ in 0..2 {
+            full |= matches!(queue.send(&b"more"[..]).await, Err(WriteError::Full));
+        }
+        assert!(full);
+        assert!(matches!(queue.close().await, Err(WriteError::Timeout { .. })));
+        Ok(())
+    }
+}
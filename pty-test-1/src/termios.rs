@@ -0,0 +1,248 @@
+use std::fmt;
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// The control characters in `termios.c_cc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlChar {
+    /// Sends SIGINT to the foreground process group, usually ^C
+    Intr,
+    /// Sends SIGQUIT, usually ^\
+    Quit,
+    /// Erase the last character, usually DEL
+    Erase,
+    /// Erase the line, usually ^U
+    Kill,
+    /// End of file, usually ^D
+    Eof,
+    Eol,
+    /// Sends SIGTSTP, usually ^Z
+    Susp,
+    Start,
+    Stop,
+    /// Minimum number of bytes for a noncanonical read
+    Min,
+    /// Timeout in deciseconds for a noncanonical read
+    Time,
+}
+
+impl ControlChar {
+    fn index(self) -> usize {
+        match self {
+            ControlChar::Intr => libc::VINTR,
+            ControlChar::Quit => libc::VQUIT,
+            ControlChar::Erase => libc::VERASE,
+            ControlChar::Kill => libc::VKILL,
+            ControlChar::Eof => libc::VEOF,
+            ControlChar::Eol => libc::VEOL,
+            ControlChar::Susp => libc::VSUSP,
+            ControlChar::Start => libc::VSTART,
+            ControlChar::Stop => libc::VSTOP,
+            ControlChar::Min => libc::VMIN,
+            ControlChar::Time => libc::VTIME,
+        }
+    }
+}
+
+/// Terminal attributes of a pty.
+///
+/// Read them with `Termios::from_fd`, change them, then `apply` them.
+/// Changes made through either side of a pty apply to the pair.
+#[derive(Clone, Copy)]
+pub struct Termios {
+    inner: libc::termios,
+}
+
+impl Termios {
+    /// Get the attributes of the terminal `fd`
+    pub fn from_fd(fd: RawFd) -> Result<Self, failure::Error> {
+        let mut inner: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut inner) } != 0 {
+            log::error!("Failed to tcgetattr: {:?}", io::Error::last_os_error());
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self { inner })
+    }
+
+    /// Attributes for a raw 8 bit channel, suitable for binary protocols.
+    /// Reads return as soon as a byte is available.  cfmakeraw leaves the
+    /// receiver alone, so it is turned on here.
+    pub fn raw() -> Self {
+        let mut termios = Self { inner: unsafe { std::mem::zeroed() } };
+        termios.make_raw();
+        termios.inner.c_cflag |= libc::CREAD | libc::CS8;
+        termios.set_control_char(ControlChar::Min, 1);
+        termios.set_control_char(ControlChar::Time, 0);
+        termios.set_speed(libc::B38400);
+        termios
+    }
+
+    /// Apply the attributes to the terminal `fd` immediately
+    pub fn apply(&self, fd: RawFd) -> Result<(), failure::Error> {
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &self.inner) } != 0 {
+            log::error!("Failed to tcsetattr: {:?}", io::Error::last_os_error());
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Disable all input and output processing, echo, line editing and signals
+    pub fn make_raw(&mut self) {
+        unsafe { libc::cfmakeraw(&mut self.inner) };
+    }
+
+    pub fn is_raw(&self) -> bool {
+        !self.echo() && !self.canonical() && !self.isig()
+            && self.inner.c_oflag & libc::OPOST == 0
+    }
+
+    pub fn echo(&self) -> bool {
+        self.inner.c_lflag & libc::ECHO != 0
+    }
+
+    pub fn set_echo(&mut self, on: bool) {
+        set_flag(&mut self.inner.c_lflag, libc::ECHO, on);
+    }
+
+    /// Canonical mode: input is made available line by line
+    pub fn canonical(&self) -> bool {
+        self.inner.c_lflag & libc::ICANON != 0
+    }
+
+    pub fn set_canonical(&mut self, on: bool) {
+        set_flag(&mut self.inner.c_lflag, libc::ICANON, on);
+    }
+
+    /// Whether the INTR, QUIT and SUSP characters generate signals
+    pub fn isig(&self) -> bool {
+        self.inner.c_lflag & libc::ISIG != 0
+    }
+
+    pub fn set_isig(&mut self, on: bool) {
+        set_flag(&mut self.inner.c_lflag, libc::ISIG, on);
+    }
+
+    pub fn control_char(&self, c: ControlChar) -> u8 {
+        self.inner.c_cc[c.index()] as u8
+    }
+
+    pub fn set_control_char(&mut self, c: ControlChar, value: u8) {
+        self.inner.c_cc[c.index()] = value as libc::cc_t;
+    }
+
+    /// The output baud rate, as one of the `libc::B*` constants
+    pub fn speed(&self) -> libc::speed_t {
+        unsafe { libc::cfgetospeed(&self.inner) }
+    }
+
+    pub fn input_speed(&self) -> libc::speed_t {
+        unsafe { libc::cfgetispeed(&self.inner) }
+    }
+
+    /// Set both input and output baud rate, using the `libc::B*` constants
+    pub fn set_speed(&mut self, speed: libc::speed_t) {
+        unsafe {
+            libc::cfsetispeed(&mut self.inner, speed);
+            libc::cfsetospeed(&mut self.inner, speed);
+        }
+    }
+
+    pub fn as_raw(&self) -> &libc::termios {
+        &self.inner
+    }
+
+    pub fn as_raw_mut(&mut self) -> &mut libc::termios {
+        &mut self.inner
+    }
+}
+
+impl From<libc::termios> for Termios {
+    fn from(inner: libc::termios) -> Self {
+        Self { inner }
+    }
+}
+
+impl fmt::Debug for Termios {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Termios")
+            .field("echo", &self.echo())
+            .field("canonical", &self.canonical())
+            .field("isig", &self.isig())
+            .field("iflag", &format_args!("{:#o}", self.inner.c_iflag))
+            .field("oflag", &format_args!("{:#o}", self.inner.c_oflag))
+            .field("cflag", &format_args!("{:#o}", self.inner.c_cflag))
+            .field("lflag", &format_args!("{:#o}", self.inner.c_lflag))
+            .field("speed", &self.speed())
+            .finish()
+    }
+}
+
+/// Puts a terminal in raw mode, and restores the attributes it had when
+/// dropped, including while unwinding from a panic
+pub struct RawModeGuard {
+    fd: RawFd,
+    saved: Termios,
+}
+
+impl RawModeGuard {
+    pub fn new(fd: RawFd) -> Result<Self, failure::Error> {
+        let saved = Termios::from_fd(fd)?;
+        let mut raw = saved;
+        raw.make_raw();
+        raw.apply(fd)?;
+        Ok(Self { fd, saved })
+    }
+
+    /// The attributes that will be restored
+    pub fn saved(&self) -> &Termios {
+        &self.saved
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.saved.apply(self.fd) {
+            log::error!("Failed to restore terminal attributes: {:?}", e);
+        }
+    }
+}
+
+fn set_flag(flags: &mut libc::tcflag_t, flag: libc::tcflag_t, on: bool) {
+    if on {
+        *flags |= flag;
+    } else {
+        *flags &= !flag;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::PtyCommandBuilder;
+    use crate::test::{openpty_with_termios, PtySize};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_raw_pty() -> Result<(), failure::Error> {
+        let raw = Termios::raw();
+        assert!(raw.is_raw());
+        assert_ne!(raw.as_raw().c_cflag & libc::CREAD, 0);
+
+        let (mut master, slave) = openpty_with_termios(PtySize::default(), Some(&raw))?;
+        assert!(slave.termios()?.is_raw());
+        let mut command = PtyCommandBuilder::new("sh");
+        command.arg("-c").arg("head -c 4 | od -An -tx1");
+        let (mut child, _stdio) = command.spawn(&slave)?;
+        drop(slave);
+
+        // no signal for ^C, no EOF for ^D, no CR to NL, and no echo
+        master.write_all(b"\x03\x04\r\x00").await?;
+        let mut output = vec![];
+        tokio::time::timeout(Duration::from_secs(10), master.read_to_end(&mut output)).await??;
+        // and no NL to CRNL on the way out
+        assert_eq!(String::from_utf8(output)?, " 03 04 0d 00\n");
+        assert!(child.wait().await?.success());
+        Ok(())
+    }
+}
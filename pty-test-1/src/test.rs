@@ -6,14 +6,16 @@ use std::os::unix::io::FromRawFd;
 use std::os::unix::process::CommandExt;
 use std::io::{Read, Write};
 use std::ptr;
+use std::ffi::CString;
 use io_extras::os::rustix::{AsRawFd, AsRawReadWriteFd, AsReadWriteFd, RawFd};
 use io_lifetimes::{AsFd, BorrowedFd};
 use std::fmt::{self, Debug};
-use std::io::IoSlice;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::unix::AsyncFd;
 use failure::ResultExt;
+use crate::termios::Termios;
 /// Represents the size of the visible display area in the pty
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
@@ -47,10 +49,88 @@ pub struct UnixMasterPty {
     pub fd: PtyFd
 }
 
+impl From<PtySize> for libc::winsize {
+    fn from(size: PtySize) -> Self {
+        libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: size.pixel_width,
+            ws_ypixel: size.pixel_height,
+        }
+    }
+}
+
+impl From<libc::winsize> for PtySize {
+    fn from(size: libc::winsize) -> Self {
+        PtySize {
+            rows: size.ws_row,
+            cols: size.ws_col,
+            pixel_width: size.ws_xpixel,
+            pixel_height: size.ws_ypixel,
+        }
+    }
+}
+
 impl UnixMasterPty {
+    /// The clone shares the open file description with the master, so it
+    /// is also in nonblocking mode; reads return WouldBlock when no data is ready.
     fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>, failure::Error> {
         let fd = self.fd.try_clone()?;
-        Ok(Box::new(PtyFd::from_fd(fd)?))
+        Ok(Box::new(PtyFd { registration: Registration::Blocking, fd }))
+    }
+
+    /// Change the size of the pty.
+    /// The kernel delivers SIGWINCH to the foreground process group of the
+    /// slave, which is why the child needs the slave as its controlling terminal.
+    pub fn resize(&self, size: PtySize) -> Result<(), failure::Error> {
+        resize_fd(self.fd.as_raw_fd(), size)
+    }
+
+    /// Get the current size of the pty
+    pub fn get_size(&self) -> Result<PtySize, failure::Error> {
+        get_size_fd(self.fd.as_raw_fd())
+    }
+
+    /// Get the terminal attributes of the pty
+    pub fn termios(&self) -> Result<Termios, failure::Error> {
+        Termios::from_fd(self.fd.as_raw_fd())
+    }
+
+    /// Set the terminal attributes of the pty
+    pub fn set_termios(&self, termios: &Termios) -> Result<(), failure::Error> {
+        termios.apply(self.fd.as_raw_fd())
+    }
+}
+
+impl AsyncRead for UnixMasterPty {
+    #[inline]
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.fd).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixMasterPty {
+    #[inline]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.fd).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.fd).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.fd).poll_shutdown(cx)
     }
 }
 
@@ -61,6 +141,23 @@ pub struct UnixSlavePty {
 }
 
 impl UnixSlavePty {
+    /// Get the terminal attributes of the pty
+    pub fn termios(&self) -> Result<Termios, failure::Error> {
+        Termios::from_fd(self.fd.as_raw_fd())
+    }
+
+    /// Set the terminal attributes of the pty
+    pub fn set_termios(&self, termios: &Termios) -> Result<(), failure::Error> {
+        termios.apply(self.fd.as_raw_fd())
+    }
+
+    /// Put the pty into raw mode: no echo, no line editing, no signals
+    pub fn set_raw_mode(&self) -> Result<(), failure::Error> {
+        let mut termios = self.termios()?;
+        termios.make_raw();
+        self.set_termios(&termios)
+    }
+
     pub fn spawn_command(
         &self,
         builder: tokio::process::Command,
@@ -69,10 +166,29 @@ impl UnixSlavePty {
     }
 }
 
+/// A pty file descriptor.
+///
+/// Async IO is only available when the fd was created with `from_fd_async`,
+/// which switches it to nonblocking mode.  It is registered with the tokio
+/// reactor on the first async read or write, so it can be created, and used
+/// synchronously, outside a runtime.
+/// The slave side stays blocking, because the child inherits it and
+/// applications assume blocking stdio.
 #[derive(Debug)]
 pub struct PtyFd {
+    // declared before `fd` so that it is deregistered before the fd is closed
+    registration: Registration,
     pub fd: FileDescriptor,
-    pub stream: tokio::net::UnixStream
+}
+
+/// How a `PtyFd` waits for readiness
+#[derive(Debug)]
+enum Registration {
+    /// A blocking fd, for synchronous IO only
+    Blocking,
+    /// Nonblocking, registered with tokio on the first async read or write
+    Unregistered,
+    Tokio(AsyncFd<RawFd>),
 }
 
 impl std::ops::Deref for PtyFd {
@@ -89,92 +205,190 @@ impl std::ops::DerefMut for PtyFd {
 }
 
 impl PtyFd {
-    pub fn from_fd(fd: FileDescriptor) -> Result<Self, failure::Error> {
-        let mut std = unsafe {std::os::unix::net::UnixStream::from_raw_fd(fd.as_raw_fd()) };
-
+    /// Wrap a blocking fd, for synchronous IO only
+    pub fn from_fd(mut fd: FileDescriptor) -> Result<Self, failure::Error> {
         // make sure we are blocking, or pty won't work
         // applications assume blocking, or you will get a Resource Not Available error
-        std.set_nonblocking(false)?;
-
-        let mut stream = tokio::net::UnixStream::from_std(std)?;
-        Ok(Self { fd, stream })
+        fd.set_non_blocking(false)?;
+        Ok(Self { registration: Registration::Blocking, fd })
     }
 
     pub fn from_raw_fd(raw_fd: RawFd) -> Result<Self, failure::Error> {
         let fd = unsafe {FileDescriptor::from_raw_fd(raw_fd)};
-        let mut std = unsafe {std::os::unix::net::UnixStream::from_raw_fd(raw_fd)};
+        Self::from_fd(fd)
+    }
 
-        // make sure we are blocking, or pty won't work
-        // applications assume blocking, or you will get a Resource Not Available error
-        std.set_nonblocking(false)?;
-
-        let mut stream = tokio::net::UnixStream::from_std(std)?;
-        Ok(Self { fd, stream })
-    }
-
-    pub fn spawn_command(&self, mut cmd: tokio::process::Command) -> Result<tokio::process::Child, failure::Error> {
-        unsafe {
-            cmd.pre_exec(move || {
-                    // Clean up a few things before we exec the program
-                    // Clear out any potentially problematic signal
-                    // dispositions that we might have inherited
-                    for signo in &[
-                        libc::SIGCHLD,
-                        libc::SIGHUP,
-                        libc::SIGINT,
-                        libc::SIGQUIT,
-                        libc::SIGTERM,
-                        libc::SIGALRM,
-                    ] {
-                        libc::signal(*signo, libc::SIG_DFL);
-                    }
+    /// Wrap an fd for async IO.
+    /// The fd is put into nonblocking mode now, and registered with the
+    /// tokio reactor when it is first polled, which must be within a runtime.
+    pub fn from_fd_async(mut fd: FileDescriptor) -> Result<Self, failure::Error> {
+        fd.set_non_blocking(true)?;
+        Ok(Self { registration: Registration::Unregistered, fd })
+    }
 
-                    // Establish ourselves as a session leader.
-                    if libc::setsid() == -1 {
-                        log::error!("Unable to set SID: {:?}", io::Error::last_os_error());
+    pub fn from_raw_fd_async(raw_fd: RawFd) -> Result<Self, failure::Error> {
+        let fd = unsafe {FileDescriptor::from_raw_fd(raw_fd)};
+        Self::from_fd_async(fd)
+    }
+
+    pub fn spawn_command(&self, cmd: tokio::process::Command) -> Result<tokio::process::Child, failure::Error> {
+        self.spawn_command_with(cmd, PreExec::default())
+    }
+
+    /// Spawn the command, applying `pre_exec` in the child before exec.
+    ///
+    /// Everything the child needs is prepared here, before the fork.
+    /// A tokio process is multithreaded, so between fork and exec the child
+    /// may only make async-signal-safe calls: no allocation, no locks and
+    /// no logging.
+    pub fn spawn_command_with(
+        &self,
+        cmd: tokio::process::Command,
+        pre_exec: PreExec,
+    ) -> Result<tokio::process::Child, failure::Error> {
+        // stdin is not necessarily the pty, so use our own fd, which is
+        // still open in the child until exec
+        spawn_with_tty(cmd, Tty::Fd(self.fd.as_raw_fd()), pre_exec)
+    }
+
+}
+
+/// Where the child finds its controlling terminal
+pub(crate) enum Tty {
+    /// A slave fd of ours, which stays open in the child until exec
+    Fd(RawFd),
+    /// The path of the slave, opened in the child after `setsid` and
+    /// put on each of stdin, stdout and stderr that is marked true
+    Path(CString, [bool; 3]),
+}
+
+pub(crate) fn spawn_with_tty(
+    mut cmd: tokio::process::Command,
+    tty: Tty,
+    pre_exec: PreExec,
+) -> Result<tokio::process::Child, failure::Error> {
+    let mut prepared = pre_exec.prepare()?;
+
+    unsafe {
+        cmd.pre_exec(move || {
+                // Clean up a few things before we exec the program
+                // Clear out any potentially problematic signal
+                // dispositions that we might have inherited
+                for signo in &[
+                    libc::SIGCHLD,
+                    libc::SIGHUP,
+                    libc::SIGINT,
+                    libc::SIGQUIT,
+                    libc::SIGTERM,
+                    libc::SIGALRM,
+                ] {
+                    libc::signal(*signo, libc::SIG_DFL);
+                }
+
+                // Don't inherit a blocked signal mask from the thread we forked from
+                let mut set: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigprocmask(libc::SIG_SETMASK, &set, ptr::null_mut());
+
+                // Establish ourselves as a session leader.
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                let tty_fd = match &tty {
+                    Tty::Fd(fd) => *fd,
+                    Tty::Path(path, _) => {
+                        let fd = libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+                        if fd == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        fd
+                    }
+                };
+
+                // Clippy wants us to explicitly cast TIOCSCTTY using
+                // type::from(), but the size and potentially signedness
+                // are system dependent, which is why we're using `as _`.
+                // Suppress this lint for this section of code.
+                #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_lossless))]
+                {
+                    // Set the pty as the controlling terminal.
+                    // Failure to do this means that delivery of
+                    // SIGWINCH won't happen when we resize the
+                    // terminal, among other undesirable effects.
+                    if libc::ioctl(tty_fd, libc::TIOCSCTTY as _, 0) == -1 {
                         return Err(io::Error::last_os_error());
                     }
+                }
 
-                    // Clippy wants us to explicitly cast TIOCSCTTY using
-                    // type::from(), but the size and potentially signedness
-                    // are system dependent, which is why we're using `as _`.
-                    // Suppress this lint for this section of code.
-                    #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_lossless))]
-                    {
-                        // Set the pty as the controlling terminal.
-                        // Failure to do this means that delivery of
-                        // SIGWINCH won't happen when we resize the
-                        // terminal, among other undesirable effects.
-                        if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
-                            log::error!("Unable to set TTY: {:?}", io::Error::last_os_error());
+                if let Tty::Path(_, stdio) = &tty {
+                    for (target, on_tty) in stdio.iter().enumerate() {
+                        if *on_tty && libc::dup2(tty_fd, target as RawFd) == -1 {
                             return Err(io::Error::last_os_error());
                         }
                     }
+                    if tty_fd > libc::STDERR_FILENO {
+                        libc::close(tty_fd);
+                    }
+                }
+
+                prepared.apply()?;
+
+                Ok(())
+            })
+    };
 
-                    close_random_fds();
+    let mut child = cmd.spawn()?;
 
-                    //if let Some(mask) = configured_umask {
-                        //libc::umask(mask);
-                    //}
+    // Ensure that we close out the slave fds that Child retains;
+    // they are not what we need (we need the master side to reference
+    // them) and won't work in the usual way anyway.
+    // In practice these are None, but it seems best to be move them
+    // out in case the behavior of Command changes in the future.
+    child.stdin.take();
+    child.stdout.take();
+    child.stderr.take();
 
-                    Ok(())
-                })
-        };
+    Ok(child)
+}
+
+/// Set the size of the pty behind `fd`, for backends that only have the raw master
+pub fn resize_fd(fd: RawFd, size: PtySize) -> Result<(), failure::Error> {
+    let ws_size: libc::winsize = size.into();
 
-        let mut child = cmd.spawn()?;
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ as _, &ws_size as *const _) } != 0 {
+        log::error!("Failed to ioctl(TIOCSWINSZ): {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
+    }
 
-        // Ensure that we close out the slave fds that Child retains;
-        // they are not what we need (we need the master side to reference
-        // them) and won't work in the usual way anyway.
-        // In practice these are None, but it seems best to be move them
-        // out in case the behavior of Command changes in the future.
-        child.stdin.take();
-        child.stdout.take();
-        child.stderr.take();
+    Ok(())
+}
 
-        Ok(child)
+/// Get the size of the terminal `fd`, which can be any tty, not just a pty master
+pub fn get_size_fd(fd: RawFd) -> Result<PtySize, failure::Error> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ as _, &mut size as *mut _) } != 0 {
+        log::error!("Failed to ioctl(TIOCGWINSZ): {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
     }
 
+    Ok(size.into())
+}
+
+/// Register `fd` with tokio if it isn't yet
+fn async_fd(registration: &mut Registration, fd: RawFd) -> io::Result<&AsyncFd<RawFd>> {
+    if let Registration::Unregistered = registration {
+        // AsyncFd::new panics outside a runtime, make that an error instead
+        if tokio::runtime::Handle::try_current().is_err() {
+            return Err(io::Error::new(io::ErrorKind::Other, "PtyFd async io needs a tokio runtime"));
+        }
+        *registration = Registration::Tokio(AsyncFd::new(fd)?);
+    }
+    match registration {
+        Registration::Tokio(stream) => Ok(stream),
+        _ => Err(io::Error::new(io::ErrorKind::Other, "PtyFd was not created for async io")),
+    }
 }
 
 impl Read for PtyFd {
@@ -193,13 +407,34 @@ impl Read for PtyFd {
 }
 
 impl AsyncRead for PtyFd {
-    #[inline]
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.stream).poll_read(cx, buf)
+        let this = self.get_mut();
+        let stream = async_fd(&mut this.registration, this.fd.as_raw_fd())?;
+        loop {
+            let mut guard = match stream.poll_read_ready(cx) {
+                Poll::Ready(r) => r?,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|_| this.fd.read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(ref e)) if e.raw_os_error() == Some(libc::EIO) => {
+                    // Same as the sync Read impl, the slave has been closed
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // spurious readiness, clear it and wait again
+                Err(_would_block) => continue,
+            }
+        }
     }
 }
 
@@ -214,36 +449,40 @@ impl Write for PtyFd {
 }
 
 impl AsyncWrite for PtyFd {
-    #[inline]
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.stream).poll_write(cx, buf)
-    }
-
-    fn poll_write_vectored(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        bufs: &[IoSlice<'_>],
-    ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+        let this = self.get_mut();
+        let stream = async_fd(&mut this.registration, this.fd.as_raw_fd())?;
+        loop {
+            let mut guard = match stream.poll_write_ready(cx) {
+                Poll::Ready(r) => r?,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match guard.try_io(|_| this.fd.write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
     }
 
     #[inline]
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.stream).poll_flush(cx)
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // writes go straight to the fd, there is nothing buffered here
+        Poll::Ready(Ok(()))
     }
 
     #[inline]
-    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.stream).poll_shutdown(cx)
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 }
 
 /// Helper function to set the close-on-exec flag for a raw descriptor
-fn cloexec(fd: RawFd) -> Result<(), io::Error> {
+pub(crate) fn cloexec(fd: RawFd) -> Result<(), io::Error> {
     let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
     if flags == -1 {
         log::error!( "fcntl to read flags failed: {:?}", io::Error::last_os_error());
@@ -257,16 +496,23 @@ fn cloexec(fd: RawFd) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Open a new pty pair.
+/// The master is nonblocking and registered with the tokio reactor, so this
+/// must be called from within a tokio runtime.
 pub fn openpty(size: PtySize) -> Result<(UnixMasterPty, UnixSlavePty), failure::Error> {
+    openpty_with_termios(size, None)
+}
+
+/// Open a new pty pair with the given terminal attributes, or the system
+/// defaults if `termios` is None
+pub fn openpty_with_termios(
+    size: PtySize,
+    termios: Option<&Termios>,
+) -> Result<(UnixMasterPty, UnixSlavePty), failure::Error> {
     let mut master: RawFd = -1;
     let mut slave: RawFd = -1;
 
-    let mut size = libc::winsize {
-        ws_row: size.rows,
-        ws_col: size.cols,
-        ws_xpixel: size.pixel_width,
-        ws_ypixel: size.pixel_height,
-    };
+    let mut size: libc::winsize = size.into();
 
     let result = unsafe {
         // BSDish systems may require mut pointers to some args
@@ -275,7 +521,7 @@ pub fn openpty(size: PtySize) -> Result<(UnixMasterPty, UnixSlavePty), failure::
             &mut master,
             &mut slave,
             ptr::null_mut(),
-            ptr::null_mut(),
+            termios.map_or(ptr::null(), |t| t.as_raw() as *const _) as *mut _,
             &mut size,
         )
     };
@@ -289,7 +535,7 @@ pub fn openpty(size: PtySize) -> Result<(UnixMasterPty, UnixSlavePty), failure::
     }
 
     let master = UnixMasterPty {
-        fd: PtyFd::from_raw_fd(master)?
+        fd: PtyFd::from_raw_fd_async(master)?
     };
 
     let slave = UnixSlavePty {
@@ -307,6 +553,157 @@ pub fn openpty(size: PtySize) -> Result<(UnixMasterPty, UnixSlavePty), failure::
 
 }
 
+/// Setup applied in the child by `PtyFd::spawn_command_with`
+#[derive(Debug, Clone, Default)]
+pub struct PreExec {
+    /// umask to set before exec
+    pub umask: Option<libc::mode_t>,
+    /// Descriptors passed to the child, as (fd in the parent, fd in the child).
+    /// Every other descriptor above stderr is closed on exec.
+    /// The parent fds only need to stay open until the spawn returns.
+    pub fds: Vec<(RawFd, RawFd)>,
+}
+
+impl PreExec {
+    /// Allocate everything applying this needs, for backends that fork
+    /// the child themselves
+    pub fn prepare(self) -> Result<PreparedPreExec, failure::Error> {
+        Ok(PreparedPreExec { umask: self.umask, fds: ChildFds::new(self.fds)? })
+    }
+}
+
+/// A `PreExec` ready to apply in a child
+#[derive(Debug, Clone)]
+pub struct PreparedPreExec {
+    umask: Option<libc::mode_t>,
+    fds: ChildFds,
+}
+
+impl PreparedPreExec {
+    /// Pass on the inherited descriptors and set the umask.
+    /// Runs between fork and exec, and is async-signal-safe.
+    ///
+    /// # Safety
+    ///
+    /// Only call this in a freshly forked child that is about to exec.
+    pub unsafe fn apply(&mut self) -> io::Result<()> {
+        self.fds.apply()?;
+        if let Some(mask) = self.umask {
+            libc::umask(mask);
+        }
+        Ok(())
+    }
+}
+
+/// Everything needed to remap and close descriptors in the child,
+/// allocated in the parent so that `apply` doesn't have to.
+#[derive(Debug, Clone)]
+struct ChildFds {
+    fds: Vec<(RawFd, RawFd)>,
+    // scratch space for the temporary dups, one per entry in fds
+    tmp: Vec<RawFd>,
+    // the targets above stderr, sorted, which must survive the close
+    keep: Vec<RawFd>,
+    // temporary dups are made at or above this, clear of every target
+    tmp_base: RawFd,
+    // upper bound for the fcntl(2) loop when close_range(2) is not available
+    max_fd: RawFd,
+}
+
+impl ChildFds {
+    fn new(fds: Vec<(RawFd, RawFd)>) -> Result<Self, failure::Error> {
+        let mut keep = vec![];
+        for &(src, dst) in &fds {
+            if src < 0 || dst < 0 {
+                return Err(failure::format_err!("Invalid fd mapping: {} -> {}", src, dst));
+            }
+            if fds.iter().filter(|(_, d)| *d == dst).count() > 1 {
+                return Err(failure::format_err!("fd {} is mapped more than once", dst));
+            }
+            if dst > 2 {
+                keep.push(dst);
+            }
+        }
+        keep.sort_unstable();
+
+        let tmp_base = fds.iter().map(|&(_, dst)| dst + 1).max().unwrap_or(0).max(3);
+        let tmp = vec![-1; fds.len()];
+        Ok(Self { fds, tmp, keep, tmp_base, max_fd: max_open_fds() })
+    }
+
+    /// Runs between fork and exec, must stay async-signal-safe
+    unsafe fn apply(&mut self) -> io::Result<()> {
+        // Move every source out of the way first, so that one mapping
+        // can't clobber the source of another
+        for (i, &(src, _)) in self.fds.iter().enumerate() {
+            let fd = libc::fcntl(src, libc::F_DUPFD_CLOEXEC, self.tmp_base);
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            self.tmp[i] = fd;
+        }
+
+        // dup2 clears close-on-exec on the target
+        for (i, &(_, dst)) in self.fds.iter().enumerate() {
+            if libc::dup2(self.tmp[i], dst) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // Close everything else above stderr on exec.  Closing them now would
+        // also close std's pipe that reports a failed exec to the parent.
+        let mut low = 3;
+        for &fd in &self.keep {
+            if fd > low {
+                cloexec_fd_range(low, (fd - 1) as libc::c_uint, self.max_fd);
+            }
+            low = fd + 1;
+        }
+        cloexec_fd_range(low, libc::c_uint::MAX, self.max_fd);
+        Ok(())
+    }
+}
+
+/// Upper bound on descriptor numbers for the fcntl(2) fallback: the highest
+/// fd open now.  Anything std opens later is close-on-exec already.
+fn max_open_fds() -> RawFd {
+    let highest = std::fs::read_dir("/dev/fd").ok().and_then(|dir| {
+        dir.filter_map(|entry| entry.ok()?.file_name().into_string().ok()?.parse::<RawFd>().ok())
+            .max()
+    });
+    if let Some(fd) = highest {
+        return fd;
+    }
+
+    let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0
+        && limit.rlim_cur != libc::RLIM_INFINITY
+    {
+        return limit.rlim_cur.min(65536) as RawFd;
+    }
+    65536
+}
+
+/// Mark every descriptor in `first..=last` close-on-exec, async-signal-safe.
+/// Uses close_range(2) where available and falls back to fcntl(2) on each fd
+/// up to `max_fd`.
+unsafe fn cloexec_fd_range(first: RawFd, last: libc::c_uint, max_fd: RawFd) {
+    #[cfg(target_os = "linux")]
+    {
+        if libc::syscall(libc::SYS_close_range, first as libc::c_uint, last, libc::CLOSE_RANGE_CLOEXEC) == 0 {
+            return;
+        }
+    }
+
+    let last = (last.min(RawFd::MAX as libc::c_uint) as RawFd).min(max_fd);
+    let mut fd = first;
+    while fd <= last {
+        // FD_CLOEXEC is the only descriptor flag
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        fd += 1;
+    }
+}
+
 /// On Big Sur, Cocoa leaks various file descriptors to child processes,
 /// so we need to make a pass through the open descriptors beyond just the
 /// stdio descriptors and close them all out.
@@ -324,6 +721,9 @@ pub fn openpty(size: PtySize) -> Result<(UnixMasterPty, UnixSlavePty), failure::
 /// The implementation of this function relies on `/dev/fd` being available
 /// to provide the list of open fds.  Any errors in enumerating or closing
 /// the fds are silently ignored.
+///
+/// This allocates, so it must not be called between fork and exec in a
+/// multithreaded process; `PtyFd::spawn_command` marks them close-on-exec instead.
 pub fn close_random_fds() {
     // FreeBSD, macOS and presumably other BSDish systems have /dev/fd as
     // a directory listing the current fd numbers for the process.
@@ -352,3 +752,61 @@ pub fn close_random_fds() {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::{openpty, PtyFd, PtySize};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn test_async_io() -> Result<(), failure::Error> {
+        let (master, slave) = openpty(PtySize::default())?;
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c").arg("read x; echo got $x");
+        cmd.stdin(slave.fd.as_stdio()?).stdout(slave.fd.as_stdio()?).stderr(slave.fd.as_stdio()?);
+        let mut child = slave.spawn_command(cmd)?;
+        drop(slave);
+        let mut fd = master.fd;
+
+        // nothing to read until the child has input, and waiting for it doesn't
+        // block the runtime
+        let mut buf = [0; 64];
+        tokio::select! {
+            n = fd.read(&mut buf) => panic!("read {:?} before any input", n),
+            _ = tokio::time::sleep(Duration::from_millis(200)) => (),
+        }
+
+        fd.write_all(b"hi\n").await?;
+        let mut out = vec![];
+        // the child exiting closes the slave, and the EIO that follows is EOF
+        tokio::time::timeout(TIMEOUT, fd.read_to_end(&mut out)).await??;
+        assert_eq!(String::from_utf8(out)?, "hi\r\ngot hi\r\n");
+        assert_eq!(std::io::Read::read(&mut fd, &mut buf)?, 0);
+        assert!(child.wait().await?.success());
+
+        // a blocking fd is for sync IO only
+        let (master, _slave) = openpty(PtySize::default())?;
+        let mut blocking = PtyFd::from_fd(master.fd.fd)?;
+        assert!(blocking.read(&mut buf).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_openpty_outside_a_runtime() -> Result<(), failure::Error> {
+        // the master is only registered with tokio once it is polled
+        let (mut master, mut slave) = openpty(PtySize::default())?;
+        std::io::Write::write_all(&mut slave.fd, b"x\n")?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let line = runtime.block_on(async {
+            let mut buf = [0; 64];
+            let n = tokio::time::timeout(TIMEOUT, master.fd.read(&mut buf)).await??;
+            Ok::<_, failure::Error>(String::from_utf8(buf[..n].to_vec())?)
+        })?;
+        assert_eq!(line, "x\r\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! Transcripts of child output, one JSON record per line of text.
+//!
+//! Each record has the wall clock time, the child it came from, the stream
+//! and the line, with escape sequences stripped unless asked otherwise:
+//!
+//! ```text
+//! {"time":1700000000.25,"child":"4242","stream":"stdout","line":"hello"}
+//! ```
+//!
+//! Output can come in arbitrary chunks, the writer keeps partial lines,
+//! UTF-8 sequences and escape sequences per child and stream.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::utf8::Utf8Decoder;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    /// The pty master, where stdout and stderr are mixed
+    Pty,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+            Stream::Pty => "pty",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Seconds since the Unix epoch
+    pub time: f64,
+    pub child: String,
+    pub stream: Stream,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Ground,
+    Escape,
+    Csi,
+    Osc,
+    OscEscape,
+    // DCS and the other strings that only ST ends: SOS, PM and APC
+    Dcs,
+    DcsEscape,
+}
+
+/// Removes escape sequences and control characters other than tab from
+/// text, keeping its state between calls so sequences can be split
+#[derive(Debug, Clone)]
+pub struct AnsiStripper {
+    state: AnsiState,
+}
+
+impl Default for AnsiStripper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnsiStripper {
+    pub fn new() -> Self {
+        Self { state: AnsiState::Ground }
+    }
+
+    /// Strip `text`, keeping newlines so it can still be split into lines
+    pub fn strip(&mut self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            self.state = match (self.state, c) {
+                (AnsiState::Ground, '\x1b') => AnsiState::Escape,
+                (AnsiState::Ground, '\n') | (AnsiState::Ground, '\t') => {
+                    out.push(c);
+                    AnsiState::Ground
+                }
+                (AnsiState::Ground, c) if c.is_control() => AnsiState::Ground,
+                (AnsiState::Ground, c) => {
+                    out.push(c);
+                    AnsiState::Ground
+                }
+                (AnsiState::Escape, '[') => AnsiState::Csi,
+                (AnsiState::Escape, ']') => AnsiState::Osc,
+                (AnsiState::Escape, 'P' | 'X' | '^' | '_') => AnsiState::Dcs,
+                // intermediates, as in ESC ( B
+                (AnsiState::Escape, ' '..='/') => AnsiState::Escape,
+                (AnsiState::Escape, _) => AnsiState::Ground,
+                (AnsiState::Csi, '@'..='~') => AnsiState::Ground,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+                (AnsiState::Osc, '\x07') => AnsiState::Ground,
+                (AnsiState::Osc, '\x1b') => AnsiState::OscEscape,
+                (AnsiState::Osc, _) => AnsiState::Osc,
+                (AnsiState::OscEscape, '\\') => AnsiState::Ground,
+                (AnsiState::OscEscape, _) => AnsiState::Osc,
+                (AnsiState::Dcs, '\x1b') => AnsiState::DcsEscape,
+                (AnsiState::Dcs, _) => AnsiState::Dcs,
+                (AnsiState::DcsEscape, '\\') => AnsiState::Ground,
+                (AnsiState::DcsEscape, _) => AnsiState::Dcs,
+            };
+        }
+        out
+    }
+}
+
+/// What is left over from the last chunk of one stream
+#[derive(Default)]
+struct Partial {
+    decoder: Utf8Decoder,
+    stripper: AnsiStripper,
+    line: String,
+}
+
+/// Writes transcript records to `writer`
+pub struct TranscriptWriter<W: Write> {
+    writer: W,
+    strip_ansi: bool,
+    partials: BTreeMap<(String, Stream), Partial>,
+}
+
+impl<W: Write> TranscriptWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, strip_ansi: true, partials: BTreeMap::new() }
+    }
+
+    /// Keep escape sequences and control characters in the lines, the
+    /// default is to strip them
+    pub fn preserve_ansi(mut self, preserve: bool) -> Self {
+        self.strip_ansi = !preserve;
+        self
+    }
+
+    /// Add output of `child` on `stream`.  Complete lines are written right
+    /// away, the rest waits for more output or `finish`.
+    pub fn write(&mut self, child: &str, stream: Stream, data: &[u8]) -> Result<(), failure::Error> {
+        let strip_ansi = self.strip_ansi;
+        let partial = self.partials.entry((child.to_string(), stream)).or_default();
+        let text = partial.decoder.push(data)?;
+        let text = if strip_ansi { partial.stripper.strip(&text) } else { text };
+        partial.line.push_str(&text);
+
+        let mut lines = vec![];
+        while let Some(end) = partial.line.find('\n') {
+            let rest = partial.line.split_off(end + 1);
+            lines.push(std::mem::replace(&mut partial.line, rest));
+        }
+        for line in lines {
+            self.record(child, stream, &line)?;
+        }
+        Ok(())
+    }
+
+    /// The stream has ended, write out its last line if it had no newline
+    pub fn finish(&mut self, child: &str, stream: Stream) -> Result<(), failure::Error> {
+        if let Some(mut partial) = self.partials.remove(&(child.to_string(), stream)) {
+            let text = partial.decoder.finish()?;
+            let text = if self.strip_ansi { partial.stripper.strip(&text) } else { text };
+            partial.line.push_str(&text);
+            if !partial.line.is_empty() {
+                self.record(child, stream, &partial.line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish every stream of every child, in order of child and stream
+    pub fn finish_all(&mut self) -> Result<(), failure::Error> {
+        let keys = self.partials.keys().cloned().collect::<Vec<_>>();
+        for (child, stream) in keys {
+            self.finish(&child, stream)?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, child: &str, stream: Stream, line: &str) -> Result<(), failure::Error> {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let record = Record {
+            time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64(),
+            child: child.to_string(),
+            stream,
+            line: line.to_string(),
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(mut self) -> Result<W, failure::Error> {
+        self.finish_all()?;
+        Ok(self.writer)
+    }
+}
+
+/// Read back the records of a transcript
+pub fn read_transcript<R: BufRead>(reader: R) -> Result<Vec<Record>, failure::Error> {
+    let mut records = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            records.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcript() -> Result<(), failure::Error> {
+        let mut transcript = TranscriptWriter::new(vec![]);
+        transcript.write("1", Stream::Pty, b"\x1b[1;31mred\x1b")?;
+        transcript.write("1", Stream::Stderr, b"err\n")?;
+        transcript.write("1", Stream::Pty, b"[0m done\r\n\x1b]0;title\x07two\xc3")?;
+        transcript.write("2", Stream::Stdout, b"other\n")?;
+        transcript.write("1", Stream::Pty, b"\xa9")?;
+        transcript.write("3", Stream::Pty, b"a\x1bP1$r0m\x07\x1b")?;
+        transcript.write("3", Stream::Pty, b"\\b\x1b_apc\x1b\\c\n")?;
+        let out = transcript.into_inner()?;
+
+        let records = read_transcript(&out[..])?;
+        let lines = records
+            .iter()
+            .map(|r| (r.child.as_str(), r.stream, r.line.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [
+            ("1", Stream::Stderr, "err"),
+            ("1", Stream::Pty, "red done"),
+            ("2", Stream::Stdout, "other"),
+            ("3", Stream::Pty, "abc"),
+            ("1", Stream::Pty, "twoé"),
+        ]);
+
+        let mut transcript = TranscriptWriter::new(vec![]).preserve_ansi(true);
+        transcript.write("1", Stream::Pty, b"\x1b[1mbold\x1b[0m\n")?;
+        let records = read_transcript(&transcript.into_inner()?[..])?;
+        assert_eq!(records[0].line, "\x1b[1mbold\x1b[0m");
+
+        // lines still open at the end come out in a fixed order
+        let mut transcript = TranscriptWriter::new(vec![]);
+        for (child, stream) in [("b", Stream::Pty), ("a", Stream::Stderr), ("a", Stream::Stdout)] {
+            transcript.write(child, stream, b"partial")?;
+        }
+        let records = read_transcript(&transcript.into_inner()?[..])?;
+        let open = records.iter().map(|r| (r.child.as_str(), r.stream)).collect::<Vec<_>>();
+        assert_eq!(open, [("a", Stream::Stdout), ("a", Stream::Stderr), ("b", Stream::Pty)]);
+        Ok(())
+    }
+}
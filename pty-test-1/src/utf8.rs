@@ -0,0 +1,250 @@
+//! Incremental UTF-8 decoding of pty output.
+//!
+//! A read can end in the middle of a multibyte character, so decoding each
+//! chunk on its own mangles it.  `Utf8Decoder` carries the incomplete
+//! sequence over to the next chunk instead.  It works as a
+//! `tokio_util::codec::Decoder`, or synchronously with `push` and `finish`,
+//! or as an iterator over a `Read` with `Utf8Reader`.
+
+use bytes::{Buf, BytesMut};
+use std::io::{self, Read};
+use tokio_util::codec::Decoder;
+
+/// What to do with bytes that aren't valid UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8Mode {
+    /// Replace them with U+FFFD
+    Lossy,
+    /// Fail with `io::ErrorKind::InvalidData`, after returning the text before them
+    Strict,
+}
+
+#[derive(Debug, Clone)]
+pub struct Utf8Decoder {
+    mode: Utf8Mode,
+    // incomplete sequence left by `push`; the codec keeps it in its buffer
+    carry: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub fn new(mode: Utf8Mode) -> Self {
+        Self { mode, carry: vec![] }
+    }
+
+    pub fn lossy() -> Self {
+        Self::new(Utf8Mode::Lossy)
+    }
+
+    pub fn strict() -> Self {
+        Self::new(Utf8Mode::Strict)
+    }
+
+    pub fn mode(&self) -> Utf8Mode {
+        self.mode
+    }
+
+    /// Decode the next chunk.  An incomplete sequence at the end is kept
+    /// for the next call, so the result may be empty.
+    pub fn push(&mut self, data: &[u8]) -> io::Result<String> {
+        self.carry.extend_from_slice(data);
+        self.drain(false)
+    }
+
+    /// Decode whatever is left at the end of the stream, where an incomplete
+    /// sequence is invalid
+    pub fn finish(&mut self) -> io::Result<String> {
+        self.drain(true)
+    }
+
+    fn drain(&mut self, eof: bool) -> io::Result<String> {
+        let mut text = String::new();
+        loop {
+            match self.decode_prefix(&self.carry, eof) {
+                Ok((s, used)) => {
+                    self.carry.drain(..used);
+                    text.push_str(&s);
+                    if used == 0 || self.carry.is_empty() {
+                        return Ok(text);
+                    }
+                }
+                // report the error on the next call, with the text before it intact
+                Err(_) if !text.is_empty() => return Ok(text),
+                Err((e, skip)) => {
+                    self.carry.drain(..skip);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Decode from the start of `data`, stopping before an incomplete
+    /// sequence at the end unless `eof`, and in strict mode before an invalid one.
+    /// Returns the text and the number of bytes used, or, for an invalid
+    /// sequence at the very start, the error and the length of the sequence.
+    fn decode_prefix(&self, data: &[u8], eof: bool) -> Result<(String, usize), (io::Error, usize)> {
+        let mut text = String::new();
+        let mut rest = data;
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(s) => {
+                    text.push_str(s);
+                    return Ok((text, data.len()));
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    let used = data.len() - after.len();
+                    let invalid = match e.error_len() {
+                        Some(len) => len,
+                        None if eof => after.len(),
+                        None => return Ok((text, used)),
+                    };
+
+                    match self.mode {
+                        Utf8Mode::Lossy => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[invalid..];
+                        }
+                        Utf8Mode::Strict if used > 0 => return Ok((text, used)),
+                        Utf8Mode::Strict => {
+                            let e = io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("invalid UTF-8 sequence {:?}", &after[..invalid]),
+                            );
+                            return Err((e, invalid));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode_buf(&mut self, src: &mut BytesMut, eof: bool) -> io::Result<Option<String>> {
+        match self.decode_prefix(src, eof) {
+            Ok((text, used)) => {
+                src.advance(used);
+                Ok(if text.is_empty() { None } else { Some(text) })
+            }
+            Err((e, skip)) => {
+                src.advance(skip);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Self {
+        Self::lossy()
+    }
+}
+
+impl Decoder for Utf8Decoder {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<String>> {
+        self.decode_buf(src, false)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<String>> {
+        self.decode_buf(src, true)
+    }
+}
+
+/// Reads decoded text from `inner`, one chunk per read
+pub struct Utf8Reader<R> {
+    inner: R,
+    decoder: Utf8Decoder,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> Utf8Reader<R> {
+    pub fn new(inner: R, mode: Utf8Mode) -> Self {
+        Self {
+            inner,
+            decoder: Utf8Decoder::new(mode),
+            buf: vec![0; 4096],
+            done: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for Utf8Reader<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        while !self.done {
+            let result = match self.inner.read(&mut self.buf) {
+                Ok(0) => {
+                    self.done = true;
+                    self.decoder.finish()
+                }
+                Ok(n) => self.decoder.push(&self.buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.done = true;
+                    Err(e)
+                }
+            };
+            match result {
+                Ok(text) if text.is_empty() => continue,
+                result => return Some(result),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_sequences() -> Result<(), failure::Error> {
+        let text = "añ€😀";
+        let bytes = text.as_bytes();
+        let mut decoder = Utf8Decoder::strict();
+        let mut out = String::new();
+        for b in bytes {
+            out.push_str(&decoder.push(&[*b])?);
+        }
+        out.push_str(&decoder.finish()?);
+        assert_eq!(out, text);
+
+        let mut buf = BytesMut::from(&bytes[..4]);
+        let mut decoder = Utf8Decoder::lossy();
+        assert_eq!(decoder.decode(&mut buf)?, Some("añ".to_string()));
+        assert_eq!(decoder.decode(&mut buf)?, None);
+        buf.extend_from_slice(&bytes[4..]);
+        assert_eq!(decoder.decode(&mut buf)?, Some("€😀".to_string()));
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() -> Result<(), failure::Error> {
+        let mut decoder = Utf8Decoder::lossy();
+        assert_eq!(decoder.push(b"a\xffb\xe2\x82")?, "a\u{fffd}b");
+        assert_eq!(decoder.finish()?, "\u{fffd}");
+
+        let mut decoder = Utf8Decoder::strict();
+        assert_eq!(decoder.push(b"a\xffb")?, "a");
+        assert_eq!(decoder.push(b"").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decoder.push(b"c")?, "bc");
+
+        let mut buf = BytesMut::from(&b"ok\xe2\x82"[..]);
+        assert_eq!(decoder.decode_eof(&mut buf)?, Some("ok".to_string()));
+        assert!(decoder.decode_eof(&mut buf).is_err());
+
+        let reader = Utf8Reader::new(&b"x\xe2\x82\xac\xff"[..], Utf8Mode::Lossy);
+        let chunks = reader.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(chunks.concat(), "x€\u{fffd}");
+        Ok(())
+    }
+}
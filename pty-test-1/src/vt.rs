@@ -0,0 +1,864 @@
+//! A terminal model that interprets the VT100/xterm control sequences
+//! written by programs on the pty, so that tests can look at the screen
+//! the way a user would see it.
+//!
+//! Feed it everything read from the master with `Screen::feed`.  The common
+//! subset used by full-screen programs is supported: cursor movement,
+//! erasing, insert/delete, scroll regions, SGR attributes and colors, the
+//! alternate screen, and the modes that affect input encoding.  Wide
+//! characters are treated as a single cell.
+
+use std::mem;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Default,
+    /// One of the 256 palette colors, 0-7 normal, 8-15 bright
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Color::Default
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub inverse: bool,
+    pub hidden: bool,
+    pub strikethrough: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub attrs: Attributes,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell { ch: ' ', attrs: Attributes::default() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SavedCursor {
+    row: usize,
+    col: usize,
+    attrs: Attributes,
+    origin_mode: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// ESC followed by an intermediate byte, eg the charset designators
+    EscapeIntermediate,
+    Csi,
+    Osc,
+    OscEscape,
+    /// DCS, SOS, PM and APC strings, which are ignored
+    Str,
+    StrEscape,
+}
+
+/// The screen of a virtual terminal
+#[derive(Debug, Clone)]
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    grid: Vec<Vec<Cell>>,
+    // the grid that is not being shown, primary or alternate
+    other_grid: Vec<Vec<Cell>>,
+    alternate: bool,
+    row: usize,
+    col: usize,
+    // the cursor is past the last column, the next print wraps
+    pending_wrap: bool,
+    attrs: Attributes,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    saved: SavedCursor,
+    other_saved: SavedCursor,
+    autowrap: bool,
+    origin_mode: bool,
+    insert_mode: bool,
+    cursor_visible: bool,
+    application_cursor: bool,
+    bracketed_paste: bool,
+    mouse_tracking: bool,
+    sgr_mouse: bool,
+    title: String,
+    responses: Vec<u8>,
+
+    state: State,
+    params: Vec<u16>,
+    param: Option<u16>,
+    private: Option<u8>,
+    intermediate: Option<u8>,
+    osc: Vec<u8>,
+    utf8: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        let rows = rows.max(1);
+        let cols = cols.max(1);
+        Screen {
+            rows,
+            cols,
+            grid: blank_grid(rows, cols),
+            other_grid: blank_grid(rows, cols),
+            alternate: false,
+            row: 0,
+            col: 0,
+            pending_wrap: false,
+            attrs: Attributes::default(),
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            saved: SavedCursor::default(),
+            other_saved: SavedCursor::default(),
+            autowrap: true,
+            origin_mode: false,
+            insert_mode: false,
+            cursor_visible: true,
+            application_cursor: false,
+            bracketed_paste: false,
+            mouse_tracking: false,
+            sgr_mouse: false,
+            title: String::new(),
+            responses: vec![],
+            state: State::Ground,
+            params: vec![],
+            param: None,
+            private: None,
+            intermediate: None,
+            osc: vec![],
+            utf8: vec![],
+        }
+    }
+
+    /// (rows, cols)
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// The zero based (row, col) of the cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn cell(&self, row: usize, col: usize) -> Option<&Cell> {
+        self.grid.get(row).and_then(|r| r.get(col))
+    }
+
+    /// The text of a row, without trailing whitespace
+    pub fn row(&self, row: usize) -> String {
+        match self.grid.get(row) {
+            Some(cells) => {
+                let s: String = cells.iter().map(|c| c.ch).collect();
+                s.trim_end().to_string()
+            }
+            None => String::new(),
+        }
+    }
+
+    /// The text of the whole screen, one line per row
+    pub fn contents(&self) -> String {
+        (0..self.rows).map(|r| self.row(r)).collect::<Vec<_>>().join("\n")
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn is_alternate_screen(&self) -> bool {
+        self.alternate
+    }
+
+    /// DECCKM, the cursor keys send SS3 sequences instead of CSI
+    pub fn application_cursor(&self) -> bool {
+        self.application_cursor
+    }
+
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste
+    }
+
+    /// The program asked for mouse reports, with any of modes 9, 1000, 1002 or 1003
+    pub fn mouse_tracking(&self) -> bool {
+        self.mouse_tracking
+    }
+
+    /// Mouse reports use the SGR (1006) encoding instead of X10 bytes
+    pub fn sgr_mouse(&self) -> bool {
+        self.sgr_mouse
+    }
+
+    /// The zero based, inclusive (top, bottom) rows of the scroll region
+    pub fn scroll_region(&self) -> (usize, usize) {
+        (self.scroll_top, self.scroll_bottom)
+    }
+
+    /// Replies to status queries (cursor position, device attributes),
+    /// which should be written back to the master
+    pub fn take_responses(&mut self) -> Vec<u8> {
+        mem::take(&mut self.responses)
+    }
+
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        let rows = rows.max(1);
+        let cols = cols.max(1);
+        for grid in [&mut self.grid, &mut self.other_grid] {
+            grid.resize(rows, vec![Cell::default(); cols]);
+            for r in grid.iter_mut() {
+                r.resize(cols, Cell::default());
+            }
+        }
+        self.rows = rows;
+        self.cols = cols;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.row = self.row.min(rows - 1);
+        self.col = self.col.min(cols - 1);
+        self.pending_wrap = false;
+    }
+
+    /// Interpret output from the pty
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.byte(b);
+        }
+    }
+
+    fn byte(&mut self, b: u8) {
+        // finish or abandon a multibyte character
+        if !self.utf8.is_empty() {
+            if b & 0xc0 == 0x80 {
+                self.utf8.push(b);
+                if self.utf8.len() == utf8_len(self.utf8[0]) {
+                    let s = String::from_utf8_lossy(&self.utf8).into_owned();
+                    self.utf8.clear();
+                    for c in s.chars() {
+                        self.print(c);
+                    }
+                }
+                return;
+            }
+            self.utf8.clear();
+            self.print(char::REPLACEMENT_CHARACTER);
+        }
+
+        match self.state {
+            State::Osc => match b {
+                0x07 => self.finish_osc(),
+                0x1b => self.state = State::OscEscape,
+                _ => self.osc.push(b),
+            },
+            State::OscEscape => {
+                // ST is ESC \, anything else aborts the string
+                self.finish_osc();
+                if b != b'\\' {
+                    self.byte(b);
+                }
+            }
+            State::Str => match b {
+                0x07 => self.state = State::Ground,
+                0x1b => self.state = State::StrEscape,
+                _ => (),
+            },
+            State::StrEscape => {
+                self.state = State::Ground;
+                if b != b'\\' {
+                    self.byte(b);
+                }
+            }
+            _ if b == 0x1b => {
+                self.state = State::Escape;
+                self.intermediate = None;
+            }
+            _ if b < 0x20 || b == 0x7f => self.control(b),
+            State::Ground => {
+                if b < 0x80 {
+                    self.print(b as char);
+                } else if utf8_len(b) > 1 {
+                    self.utf8.push(b);
+                } else {
+                    self.print(char::REPLACEMENT_CHARACTER);
+                }
+            }
+            State::Escape => self.escape(b),
+            State::EscapeIntermediate => {
+                // charset designation and DECALN, none of which we model
+                self.state = State::Ground;
+            }
+            State::Csi => self.csi_byte(b),
+        }
+    }
+
+    fn control(&mut self, b: u8) {
+        match b {
+            0x08 => {
+                self.col = self.col.saturating_sub(1);
+                self.pending_wrap = false;
+            }
+            0x09 => {
+                self.col = ((self.col / 8 + 1) * 8).min(self.cols - 1);
+                self.pending_wrap = false;
+            }
+            0x0a | 0x0b | 0x0c => self.linefeed(),
+            0x0d => {
+                self.col = 0;
+                self.pending_wrap = false;
+            }
+            _ => (),
+        }
+    }
+
+    fn escape(&mut self, b: u8) {
+        self.state = State::Ground;
+        match b {
+            b'[' => {
+                self.state = State::Csi;
+                self.params.clear();
+                self.param = None;
+                self.private = None;
+                self.intermediate = None;
+            }
+            b']' => {
+                self.state = State::Osc;
+                self.osc.clear();
+            }
+            b'P' | b'X' | b'^' | b'_' => self.state = State::Str,
+            b'(' | b')' | b'*' | b'+' | b'#' | b'%' => self.state = State::EscapeIntermediate,
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.col = 0;
+                self.linefeed();
+            }
+            b'M' => self.reverse_index(),
+            b'c' => {
+                let (rows, cols) = (self.rows, self.cols);
+                *self = Screen::new(rows, cols);
+            }
+            _ => (),
+        }
+    }
+
+    fn csi_byte(&mut self, b: u8) {
+        match b {
+            b'0'..=b'9' => {
+                let digit = (b - b'0') as u16;
+                self.param = Some(self.param.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            }
+            // subparameters are flattened, which is enough for colors
+            b';' | b':' => {
+                self.params.push(self.param.take().unwrap_or(0));
+            }
+            b'<' | b'=' | b'>' | b'?' => self.private = Some(b),
+            0x20..=0x2f => self.intermediate = Some(b),
+            0x40..=0x7e => {
+                if let Some(p) = self.param.take() {
+                    self.params.push(p);
+                }
+                self.state = State::Ground;
+                self.csi(b);
+            }
+            _ => self.state = State::Ground,
+        }
+    }
+
+    /// Parameter `i`, or `default` when it is missing or zero
+    fn param(&self, i: usize, default: usize) -> usize {
+        match self.params.get(i) {
+            Some(&p) if p > 0 => p as usize,
+            _ => default,
+        }
+    }
+
+    fn csi(&mut self, action: u8) {
+        if self.intermediate.is_some() {
+            return;
+        }
+
+        match (self.private, action) {
+            (None, b'A') => self.move_cursor_up(self.param(0, 1)),
+            (None, b'B') | (None, b'e') => self.move_cursor_down(self.param(0, 1)),
+            (None, b'C') | (None, b'a') => self.set_col(self.col + self.param(0, 1)),
+            (None, b'D') => self.set_col(self.col.saturating_sub(self.param(0, 1))),
+            (None, b'E') => {
+                self.move_cursor_down(self.param(0, 1));
+                self.col = 0;
+            }
+            (None, b'F') => {
+                self.move_cursor_up(self.param(0, 1));
+                self.col = 0;
+            }
+            (None, b'G') | (None, b'`') => self.set_col(self.param(0, 1) - 1),
+            (None, b'H') | (None, b'f') => self.goto(self.param(0, 1) - 1, self.param(1, 1) - 1),
+            (None, b'd') => self.goto(self.param(0, 1) - 1, self.col),
+            (_, b'J') => self.erase_display(self.param(0, 0)),
+            (_, b'K') => self.erase_line(self.param(0, 0)),
+            (None, b'L') => self.insert_lines(self.param(0, 1)),
+            (None, b'M') => self.delete_lines(self.param(0, 1)),
+            (None, b'@') => self.insert_chars(self.param(0, 1)),
+            (None, b'P') => self.delete_chars(self.param(0, 1)),
+            (None, b'X') => {
+                let end = (self.col + self.param(0, 1)).min(self.cols);
+                self.clear_cells(self.row, self.col, end);
+            }
+            (None, b'S') => self.scroll_up(self.param(0, 1)),
+            (None, b'T') => self.scroll_down(self.param(0, 1)),
+            (None, b'm') => self.sgr(),
+            (None, b'r') => {
+                let top = self.param(0, 1) - 1;
+                let bottom = self.param(1, self.rows).min(self.rows) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.goto(0, 0);
+                }
+            }
+            (None, b's') => self.save_cursor(),
+            (None, b'u') => self.restore_cursor(),
+            (None, b'n') => match self.param(0, 0) {
+                5 => self.responses.extend_from_slice(b"\x1b[0n"),
+                6 => {
+                    let report = format!("\x1b[{};{}R", self.row + 1, self.col + 1);
+                    self.responses.extend_from_slice(report.as_bytes());
+                }
+                _ => (),
+            },
+            (None, b'c') => self.responses.extend_from_slice(b"\x1b[?1;2c"),
+            (None, b'h') => self.set_modes(true),
+            (None, b'l') => self.set_modes(false),
+            (Some(b'?'), b'h') => self.set_private_modes(true),
+            (Some(b'?'), b'l') => self.set_private_modes(false),
+            _ => log::trace!("unhandled CSI {:?} {:?} {}", self.private, self.params, action as char),
+        }
+    }
+
+    fn set_modes(&mut self, on: bool) {
+        for i in 0..self.params.len() {
+            if self.params[i] == 4 {
+                self.insert_mode = on;
+            }
+        }
+    }
+
+    fn set_private_modes(&mut self, on: bool) {
+        let params = mem::take(&mut self.params);
+        for &p in &params {
+            match p {
+                1 => self.application_cursor = on,
+                6 => {
+                    self.origin_mode = on;
+                    self.goto(0, 0);
+                }
+                7 => self.autowrap = on,
+                25 => self.cursor_visible = on,
+                47 | 1047 => self.set_alternate(on, false),
+                1048 => {
+                    if on {
+                        self.save_cursor()
+                    } else {
+                        self.restore_cursor()
+                    }
+                }
+                1049 => {
+                    if on {
+                        self.save_cursor();
+                        self.set_alternate(true, true);
+                    } else {
+                        self.set_alternate(false, false);
+                        self.restore_cursor();
+                    }
+                }
+                9 | 1000 | 1002 | 1003 => self.mouse_tracking = on,
+                1006 => self.sgr_mouse = on,
+                2004 => self.bracketed_paste = on,
+                _ => (),
+            }
+        }
+        self.params = params;
+    }
+
+    fn set_alternate(&mut self, on: bool, clear: bool) {
+        if on != self.alternate {
+            mem::swap(&mut self.grid, &mut self.other_grid);
+            mem::swap(&mut self.saved, &mut self.other_saved);
+            self.alternate = on;
+        }
+        if on && clear {
+            self.grid = blank_grid(self.rows, self.cols);
+        }
+    }
+
+    fn sgr(&mut self) {
+        if self.params.is_empty() {
+            self.attrs = Attributes::default();
+            return;
+        }
+
+        let mut i = 0;
+        while i < self.params.len() {
+            let p = self.params[i];
+            match p {
+                0 => self.attrs = Attributes::default(),
+                1 => self.attrs.bold = true,
+                2 => self.attrs.dim = true,
+                3 => self.attrs.italic = true,
+                4 => self.attrs.underline = true,
+                5 | 6 => self.attrs.blink = true,
+                7 => self.attrs.inverse = true,
+                8 => self.attrs.hidden = true,
+                9 => self.attrs.strikethrough = true,
+                21 | 22 => {
+                    self.attrs.bold = false;
+                    self.attrs.dim = false;
+                }
+                23 => self.attrs.italic = false,
+                24 => self.attrs.underline = false,
+                25 => self.attrs.blink = false,
+                27 => self.attrs.inverse = false,
+                28 => self.attrs.hidden = false,
+                29 => self.attrs.strikethrough = false,
+                30..=37 => self.attrs.fg = Color::Indexed((p - 30) as u8),
+                39 => self.attrs.fg = Color::Default,
+                40..=47 => self.attrs.bg = Color::Indexed((p - 40) as u8),
+                49 => self.attrs.bg = Color::Default,
+                90..=97 => self.attrs.fg = Color::Indexed((p - 90 + 8) as u8),
+                100..=107 => self.attrs.bg = Color::Indexed((p - 100 + 8) as u8),
+                38 | 48 => {
+                    let (color, used) = self.extended_color(i + 1);
+                    if let Some(color) = color {
+                        if p == 38 {
+                            self.attrs.fg = color;
+                        } else {
+                            self.attrs.bg = color;
+                        }
+                    }
+                    i += used;
+                }
+                _ => (),
+            }
+            i += 1;
+        }
+    }
+
+    /// Parse the `5;n` or `2;r;g;b` following 38 or 48,
+    /// returning the color and how many parameters were used
+    fn extended_color(&self, i: usize) -> (Option<Color>, usize) {
+        let get = |j: usize| self.params.get(i + j).map(|&v| v.min(255) as u8);
+        match self.params.get(i) {
+            Some(5) => (get(1).map(Color::Indexed), 2),
+            Some(2) => match (get(1), get(2), get(3)) {
+                (Some(r), Some(g), Some(b)) => (Some(Color::Rgb(r, g, b)), 4),
+                _ => (None, self.params.len() - i),
+            },
+            _ => (None, 0),
+        }
+    }
+
+    fn finish_osc(&mut self) {
+        self.state = State::Ground;
+        let osc = String::from_utf8_lossy(&self.osc).into_owned();
+        self.osc.clear();
+        let mut parts = osc.splitn(2, ';');
+        match (parts.next(), parts.next()) {
+            (Some("0"), Some(title)) | (Some("2"), Some(title)) => self.title = title.to_string(),
+            _ => (),
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        if self.pending_wrap {
+            self.col = 0;
+            self.linefeed();
+        }
+
+        if self.insert_mode {
+            self.insert_chars(1);
+        }
+
+        self.grid[self.row][self.col] = Cell { ch: c, attrs: self.attrs };
+
+        if self.col + 1 < self.cols {
+            self.col += 1;
+        } else {
+            self.pending_wrap = self.autowrap;
+        }
+    }
+
+    fn linefeed(&mut self) {
+        self.pending_wrap = false;
+        if self.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.row + 1 < self.rows {
+            self.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.pending_wrap = false;
+        if self.row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.row > 0 {
+            self.row -= 1;
+        }
+    }
+
+    /// Scroll the contents of the scroll region up, blank lines appear at the bottom
+    fn scroll_up(&mut self, n: usize) {
+        let n = n.min(self.scroll_bottom - self.scroll_top + 1);
+        for _ in 0..n {
+            self.grid.remove(self.scroll_top);
+            self.grid.insert(self.scroll_bottom, vec![Cell::default(); self.cols]);
+        }
+    }
+
+    /// Scroll the contents of the scroll region down, blank lines appear at the top
+    fn scroll_down(&mut self, n: usize) {
+        let n = n.min(self.scroll_bottom - self.scroll_top + 1);
+        for _ in 0..n {
+            self.grid.remove(self.scroll_bottom);
+            self.grid.insert(self.scroll_top, vec![Cell::default(); self.cols]);
+        }
+    }
+
+    fn insert_lines(&mut self, n: usize) {
+        if self.row < self.scroll_top || self.row > self.scroll_bottom {
+            return;
+        }
+        let n = n.min(self.scroll_bottom - self.row + 1);
+        for _ in 0..n {
+            self.grid.remove(self.scroll_bottom);
+            self.grid.insert(self.row, vec![Cell::default(); self.cols]);
+        }
+        self.col = 0;
+        self.pending_wrap = false;
+    }
+
+    fn delete_lines(&mut self, n: usize) {
+        if self.row < self.scroll_top || self.row > self.scroll_bottom {
+            return;
+        }
+        let n = n.min(self.scroll_bottom - self.row + 1);
+        for _ in 0..n {
+            self.grid.remove(self.row);
+            self.grid.insert(self.scroll_bottom, vec![Cell::default(); self.cols]);
+        }
+        self.col = 0;
+        self.pending_wrap = false;
+    }
+
+    fn insert_chars(&mut self, n: usize) {
+        let n = n.min(self.cols - self.col);
+        let line = &mut self.grid[self.row];
+        for _ in 0..n {
+            line.pop();
+            line.insert(self.col, Cell::default());
+        }
+    }
+
+    fn delete_chars(&mut self, n: usize) {
+        let n = n.min(self.cols - self.col);
+        let line = &mut self.grid[self.row];
+        for _ in 0..n {
+            line.remove(self.col);
+            line.push(Cell::default());
+        }
+        self.pending_wrap = false;
+    }
+
+    fn clear_cells(&mut self, row: usize, start: usize, end: usize) {
+        for cell in &mut self.grid[row][start..end] {
+            *cell = Cell::default();
+        }
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        match mode {
+            0 => {
+                self.clear_cells(self.row, self.col, self.cols);
+                for r in self.row + 1..self.rows {
+                    self.clear_cells(r, 0, self.cols);
+                }
+            }
+            1 => {
+                for r in 0..self.row {
+                    self.clear_cells(r, 0, self.cols);
+                }
+                self.clear_cells(self.row, 0, self.col + 1);
+            }
+            2 | 3 => self.grid = blank_grid(self.rows, self.cols),
+            _ => (),
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        match mode {
+            0 => self.clear_cells(self.row, self.col, self.cols),
+            1 => self.clear_cells(self.row, 0, self.col + 1),
+            2 => self.clear_cells(self.row, 0, self.cols),
+            _ => (),
+        }
+    }
+
+    /// Move the cursor, rows are relative to the scroll region in origin mode
+    fn goto(&mut self, row: usize, col: usize) {
+        let (top, bottom) = if self.origin_mode {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.rows - 1)
+        };
+        self.row = (top + row).min(bottom);
+        self.set_col(col);
+    }
+
+    fn set_col(&mut self, col: usize) {
+        self.col = col.min(self.cols - 1);
+        self.pending_wrap = false;
+    }
+
+    /// Cursor up stops at the top margin, unless already above it
+    fn move_cursor_up(&mut self, n: usize) {
+        let top = if self.row >= self.scroll_top { self.scroll_top } else { 0 };
+        self.row = self.row.saturating_sub(n).max(top);
+        self.pending_wrap = false;
+    }
+
+    fn move_cursor_down(&mut self, n: usize) {
+        let bottom = if self.row <= self.scroll_bottom { self.scroll_bottom } else { self.rows - 1 };
+        self.row = (self.row + n).min(bottom);
+        self.pending_wrap = false;
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = SavedCursor {
+            row: self.row,
+            col: self.col,
+            attrs: self.attrs,
+            origin_mode: self.origin_mode,
+        };
+    }
+
+    fn restore_cursor(&mut self) {
+        self.row = self.saved.row.min(self.rows - 1);
+        self.col = self.saved.col.min(self.cols - 1);
+        self.attrs = self.saved.attrs;
+        self.origin_mode = self.saved.origin_mode;
+        self.pending_wrap = false;
+    }
+}
+
+fn blank_grid(rows: usize, cols: usize) -> Vec<Vec<Cell>> {
+    vec![vec![Cell::default(); cols]; rows]
+}
+
+/// Length of a UTF-8 sequence from its first byte, 1 for invalid lead bytes
+fn utf8_len(b: u8) -> usize {
+    match b {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_print_and_wrap() {
+        let mut screen = Screen::new(3, 5);
+        screen.feed(b"hello world");
+        assert_eq!(screen.row(0), "hello");
+        assert_eq!(screen.row(1), " worl");
+        assert_eq!(screen.row(2), "d");
+        assert_eq!(screen.cursor(), (2, 1));
+    }
+
+    #[test]
+    fn test_cursor_and_erase() {
+        let mut screen = Screen::new(5, 10);
+        screen.feed(b"aaaaaaaaaa\r\nbbbbbbbbbb\x1b[3;4Hx\x1b[1;5H\x1b[K\x1b[2;3H\x1b[1K");
+        assert_eq!(screen.row(0), "aaaa");
+        assert_eq!(screen.row(1), "   bbbbbbb");
+        assert_eq!(screen.row(2), "   x");
+        assert_eq!(screen.cursor(), (1, 2));
+        screen.feed(b"\x1b[2J");
+        assert_eq!(screen.contents(), "\n\n\n\n");
+    }
+
+    #[test]
+    fn test_scroll_region() {
+        let mut screen = Screen::new(4, 5);
+        screen.feed(b"top\r\n1\r\n2\r\nbot");
+        screen.feed(b"\x1b[2;3r\x1b[3;1H\n3");
+        assert_eq!(screen.contents(), "top\n2\n3\nbot");
+        screen.feed(b"\x1b[2;1H\x1bM");
+        assert_eq!(screen.contents(), "top\n\n2\nbot");
+    }
+
+    #[test]
+    fn test_alternate_screen() {
+        let mut screen = Screen::new(2, 10);
+        screen.feed(b"shell$ \x1b[?1049h\x1b[Hfull screen");
+        assert!(screen.is_alternate_screen());
+        assert_eq!(screen.row(0), "full scree");
+        screen.feed(b"\x1b[?1049l");
+        assert!(!screen.is_alternate_screen());
+        assert_eq!(screen.row(0), "shell$");
+        assert_eq!(screen.cursor(), (0, 7));
+    }
+
+    #[test]
+    fn test_attributes() {
+        let mut screen = Screen::new(1, 10);
+        screen.feed(b"\x1b[1;31ma\x1b[38;5;200;48;2;1;2;3mb\x1b[0mc");
+        let a = screen.cell(0, 0).unwrap().attrs;
+        assert!(a.bold);
+        assert_eq!(a.fg, Color::Indexed(1));
+        let b = screen.cell(0, 1).unwrap().attrs;
+        assert_eq!(b.fg, Color::Indexed(200));
+        assert_eq!(b.bg, Color::Rgb(1, 2, 3));
+        assert_eq!(screen.cell(0, 2).unwrap().attrs, Attributes::default());
+    }
+
+    #[test]
+    fn test_utf8_split_and_osc() {
+        let mut screen = Screen::new(1, 10);
+        let text = "\x1b]0;my title\x07héllo".as_bytes();
+        for b in text {
+            screen.feed(&[*b]);
+        }
+        assert_eq!(screen.row(0), "héllo");
+        assert_eq!(screen.title(), "my title");
+    }
+
+    #[test]
+    fn test_cursor_report() {
+        let mut screen = Screen::new(5, 10);
+        screen.feed(b"\x1b[3;4H\x1b[6n\x1b[?1h");
+        assert_eq!(screen.take_responses(), b"\x1b[3;4R");
+        assert!(screen.application_cursor());
+    }
+}
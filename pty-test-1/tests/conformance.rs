@@ -0,0 +1,70 @@
+use pty_test::conformance;
+use pty_test::TokioPty;
+
+fn run(check: fn() -> Result<(), failure::Error>) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    check().unwrap();
+}
+
+#[test]
+fn echo_via_cat() {
+    run(conformance::echo_via_cat::<TokioPty>);
+}
+
+#[test]
+fn tty_reports_pts_path() {
+    run(conformance::tty_reports_pts_path::<TokioPty>);
+}
+
+#[test]
+fn exit_codes() {
+    run(conformance::exit_codes::<TokioPty>);
+}
+
+#[test]
+fn eof_on_exit() {
+    run(conformance::eof_on_exit::<TokioPty>);
+}
+
+#[test]
+fn resize() {
+    run(conformance::resize::<TokioPty>);
+}
+
+#[test]
+fn signal() {
+    run(conformance::signal::<TokioPty>);
+}
+
+#[test]
+fn run_to_completion() {
+    run(conformance::run_to_completion::<TokioPty>);
+}
+
+#[test]
+fn argv0() {
+    run(conformance::argv0::<TokioPty>);
+}
+
+#[test]
+fn umask() {
+    run(conformance::umask::<TokioPty>);
+}
+
+#[test]
+fn inherited_fd() {
+    run(conformance::inherited_fd::<TokioPty>);
+}
+
+#[test]
+fn stdio_modes() {
+    run(conformance::stdio_modes::<TokioPty>);
+}
+
+#[tokio::test]
+async fn wait_inside_a_runtime() {
+    use pty_test::{Pty, PtyCommandBuilder, PtySize};
+    let mut pty = TokioPty::spawn(&PtyCommandBuilder::new("true"), PtySize::default()).unwrap();
+    assert!(pty.wait().unwrap().success());
+}
@@ -0,0 +1,118 @@
+//! Spawn real programs on a pty with the low level `openpty` api
+
+use pty_test::*;
+use std::os::unix::process::ExitStatusExt;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn spawn(size: PtySize, program: &str, args: &[&str]) -> (UnixMasterPty, tokio::process::Child) {
+    let (master, slave) = openpty(size).unwrap();
+    let mut builder = PtyCommandBuilder::new(program);
+    builder.args(args);
+    let (child, _stdio) = builder.spawn(&slave).unwrap();
+    // only the child holds the slave now, so the master sees EOF when it exits
+    drop(slave);
+    (master, child)
+}
+
+async fn read_until(master: &mut UnixMasterPty, needle: &str) -> String {
+    let mut out = vec![];
+    let mut buf = [0; 1024];
+    while !String::from_utf8_lossy(&out).contains(needle) {
+        let n = timeout(TIMEOUT, master.read(&mut buf))
+            .await
+            .unwrap_or_else(|_| panic!("no {:?} in {:?}", needle, String::from_utf8_lossy(&out)))
+            .unwrap();
+        assert!(n > 0, "EOF before {:?} in {:?}", needle, String::from_utf8_lossy(&out));
+        out.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(out).unwrap()
+}
+
+async fn read_to_end(master: &mut UnixMasterPty) -> String {
+    let mut out = vec![];
+    timeout(TIMEOUT, master.read_to_end(&mut out)).await.unwrap().unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[tokio::test]
+async fn cat_echoes_input() {
+    let (mut master, mut child) = spawn(PtySize::default(), "cat", &[]);
+    master.write_all(b"hello\n").await.unwrap();
+    // once from the line discipline echo, once from cat
+    assert_eq!(read_until(&mut master, "hello\r\nhello\r\n").await, "hello\r\nhello\r\n");
+
+    let mut termios = master.termios().unwrap();
+    termios.set_echo(false);
+    master.set_termios(&termios).unwrap();
+    master.write_all(b"quiet\n").await.unwrap();
+    assert_eq!(read_until(&mut master, "quiet\r\n").await, "quiet\r\n");
+
+    // ^D on an empty line is EOF for cat
+    master.write_all(b"\x04").await.unwrap();
+    assert_eq!(read_to_end(&mut master).await, "");
+    assert!(child.wait().await.unwrap().success());
+}
+
+#[tokio::test]
+async fn pty_is_controlling_terminal() {
+    let (mut master, mut child) = spawn(
+        PtySize::default(),
+        "sh",
+        &["-c", "tty; tty -s </dev/tty && echo ctty; ps -o stat= -p $$"],
+    );
+    let out = read_to_end(&mut master).await;
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], master.ptsname().unwrap().to_str().unwrap());
+    assert_eq!(lines[1], "ctty");
+    // a session leader in the foreground process group
+    assert!(lines[2].contains('s') && lines[2].contains('+'), "{:?}", lines[2]);
+    assert!(child.wait().await.unwrap().success());
+}
+
+#[tokio::test]
+async fn size_propagates() {
+    let size = PtySize { rows: 33, cols: 101, ..PtySize::default() };
+    let (mut master, mut child) = spawn(size, "sh", &["-c", "stty size; read x; stty size"]);
+    read_until(&mut master, "33 101\r\n").await;
+
+    let size = PtySize { rows: 12, cols: 40, ..PtySize::default() };
+    master.resize(size).unwrap();
+    assert_eq!(master.get_size().unwrap(), size);
+    master.write_all(b"\n").await.unwrap();
+    assert!(read_to_end(&mut master).await.ends_with("12 40\r\n"));
+    assert!(child.wait().await.unwrap().success());
+}
+
+#[tokio::test]
+async fn exit_code() {
+    let (mut master, mut child) = spawn(PtySize::default(), "sh", &["-c", "exit 3"]);
+    assert_eq!(read_to_end(&mut master).await, "");
+    let status = child.wait().await.unwrap();
+    assert_eq!(status.code(), Some(3));
+    assert_eq!(status.signal(), None);
+}
+
+#[tokio::test]
+async fn killed_by_signal() {
+    let (mut master, mut child) = spawn(PtySize::default(), "sh", &["-c", "kill -9 $$"]);
+    read_to_end(&mut master).await;
+    let status = child.wait().await.unwrap();
+    assert_eq!(status.code(), None);
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+}
+
+#[tokio::test]
+async fn large_output_then_eof() {
+    let (mut master, mut child) = spawn(PtySize::default(), "seq", &["1", "200000"]);
+    let out = read_to_end(&mut master).await;
+    let lines = out.split("\r\n").collect::<Vec<_>>();
+    // the final newline leaves an empty last element
+    assert_eq!(lines.len(), 200001);
+    assert_eq!(lines[0], "1");
+    assert_eq!(lines[199999], "200000");
+    assert!(child.wait().await.unwrap().success());
+}
@@ -0,0 +1,233 @@
+use filedescriptor::FileDescriptor;
+use pty_test::{Pty, PtyCommandBuilder, PtyExitStatus, PtySize};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use crate::exit::ExitNotifier;
+use crate::openpty;
+
+/// The duct backend: a blocking master from `openpty`, and a duct
+/// expression with the slave as stdin, stdout and stderr
+pub struct DuctPty {
+    master: FileDescriptor,
+    handle: duct::Handle,
+}
+
+impl DuctPty {
+    pub fn master(&self) -> &FileDescriptor {
+        &self.master
+    }
+
+    pub fn handle(&self) -> &duct::Handle {
+        &self.handle
+    }
+
+    /// Take the master and the handle, after which the child is no longer
+    /// killed on drop
+    pub fn into_parts(self) -> (FileDescriptor, duct::Handle) {
+        let this = std::mem::ManuallyDrop::new(self);
+        // the fields are moved out exactly once and `this` is never dropped
+        unsafe { (std::ptr::read(&this.master), std::ptr::read(&this.handle)) }
+    }
+
+    /// Wait for the child to exit from a tokio task, woken by its exit
+    /// rather than blocking a thread in `wait`
+    pub async fn wait_async(&mut self) -> Result<PtyExitStatus, failure::Error> {
+        if let Some(output) = self.handle.try_wait()? {
+            return Ok(output.status.into());
+        }
+        let pid = self.handle.pids()[0] as libc::pid_t;
+        ExitNotifier::new(pid)?.wait_async().await?;
+        self.wait()
+    }
+}
+
+impl Pty for DuctPty {
+    /// Only with all stdio on the pty
+    fn supports(command: &PtyCommandBuilder) -> bool {
+        command.stdio_is_pty()
+    }
+
+    fn spawn(command: &PtyCommandBuilder, size: PtySize) -> Result<Self, failure::Error> {
+        if !Self::supports(command) {
+            return Err(failure::err_msg("The duct backend only runs programs with all stdio on the pty"));
+        }
+
+        let argv0 = command.get_argv0().map(|a| a.to_owned());
+        let pre_exec = command.get_pre_exec().prepare()?;
+        let mut e = duct::cmd(command.get_program(), command.get_args()).before_spawn(move |cmd| {
+            if let Some(argv0) = &argv0 {
+                cmd.arg0(argv0);
+            }
+            let mut pre_exec = pre_exec.clone();
+            unsafe {
+                cmd.pre_exec(move || pre_exec.apply());
+            }
+            Ok(())
+        });
+
+        if let Some(cwd) = command.get_cwd() {
+            e = e.dir(cwd);
+        }
+
+        // duct applies the innermost env first, so settle each variable
+        // here to keep the builder's last-one-wins order
+        let mut envs = HashMap::new();
+        for (key, value) in command.get_envs() {
+            envs.insert(key, value);
+        }
+        if command.get_env_clear() {
+            e = e.full_env(envs.into_iter().filter_map(|(k, v)| v.map(|v| (k, v))));
+        } else {
+            for (key, value) in envs {
+                e = match value {
+                    Some(value) => e.env(key, value),
+                    None => e.env_remove(key),
+                };
+            }
+        }
+
+        e.pty(size)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.master.read(buf) {
+            // EIO means the slave has been closed
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            x => x,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn resize(&mut self, size: PtySize) -> Result<(), failure::Error> {
+        pty_test::resize_fd(self.master.as_raw_fd(), size)
+    }
+
+    fn signal(&mut self, signal: libc::c_int) -> Result<(), failure::Error> {
+        let pid = self.handle.pids()[0] as libc::pid_t;
+        if unsafe { libc::kill(pid, signal) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<PtyExitStatus, failure::Error> {
+        let output = self.handle.wait()?;
+        Ok(output.status.into())
+    }
+}
+
+/// Run duct expressions on a pty
+pub trait PtyExpressionExt {
+    /// Start the expression on a new pty of `size`, which becomes its stdin,
+    /// stdout and stderr.  The command is made a session leader with the pty
+    /// as its controlling terminal.  Only one command can be, so a pipe is
+    /// refused before anything is started; use `PtyPipeline` for those.
+    /// The expression is unchecked, a non-zero exit is just a status.
+    fn pty(&self, size: PtySize) -> Result<DuctPty, failure::Error>;
+}
+
+impl PtyExpressionExt for duct::Expression {
+    fn pty(&self, size: PtySize) -> Result<DuctPty, failure::Error> {
+        if is_pipe(self) {
+            return Err(failure::err_msg("pty() takes a single command, not a pipe, see PtyPipeline"));
+        }
+
+        let (master, slave) = openpty(size)?;
+        let slave_fd = slave.as_raw_fd();
+        let e = self
+            .stdin_file(slave.try_clone()?)
+            .stdout_file(slave.try_clone()?)
+            .stderr_file(slave.try_clone()?)
+            .unchecked()
+            .before_spawn(move |cmd| {
+                unsafe {
+                    cmd.pre_exec(move || take_controlling_tty(slave_fd));
+                }
+                Ok(())
+            });
+
+        // the expression holds the slave, drop it so that only the child has it open
+        let handle = e.start()?;
+        drop(e);
+        drop(slave);
+
+        Ok(DuctPty { master, handle })
+    }
+}
+
+/// Whether the expression runs more than one command.
+///
+/// duct can't be asked, but its Debug output mirrors the expression:
+/// `Cmd([..])`, `Pipe(left, right)` and `Io(.., inner)`, with every
+/// argument, path and variable quoted.  So outside of strings, `Pipe(`
+/// only shows up for a pipe.
+fn is_pipe(expression: &duct::Expression) -> bool {
+    let debug = format!("{:?}", expression);
+    let mut chars = debug.char_indices();
+    let mut quoted = false;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            'P' if !quoted && debug[i..].starts_with("Pipe(") => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Make the child a session leader with `slave` as its controlling
+/// terminal.  Runs between fork and exec, where `slave` is still open.
+pub(crate) fn take_controlling_tty(slave: RawFd) -> io::Result<()> {
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(slave, libc::TIOCSCTTY as _, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Drop for DuctPty {
+    fn drop(&mut self) {
+        if let Ok(None) = self.handle.try_wait() {
+            let _ = self.handle.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expression_pty() -> Result<(), failure::Error> {
+        let size = PtySize { rows: 7, cols: 33, ..PtySize::default() };
+        let mut pty = duct::cmd!("sh", "-c", "stty size; tty -s </dev/tty && echo ctty; exit 3").pty(size)?;
+        let completion = pty.run_to_completion()?;
+        assert_eq!(completion.output_lossy(), "7 33\r\nctty\r\n");
+        assert_eq!(completion.status.code, Some(3));
+
+        // only one command can have the pty as its controlling terminal
+        let piped = duct::cmd!("sleep", "30").pipe(duct::cmd!("cat")).pty(size);
+        let err = piped.err().expect("a pipe on a pty");
+        assert!(err.to_string().contains("not a pipe"), "{}", err);
+        assert!(is_pipe(&duct::cmd!("cat").pipe(duct::cmd!("cat")).dir("/").env("A", "B")));
+        assert!(!is_pipe(&duct::cmd!("echo", "Pipe(\"Pipe(", "\\").stdin_bytes("Pipe(")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conformance() -> Result<(), failure::Error> {
+        pty_test::conformance::run_all::<DuctPty>()
+    }
+}
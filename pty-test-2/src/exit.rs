@@ -0,0 +1,292 @@
+//! Child exit as an event instead of something to poll for.
+//!
+//! An `ExitNotifier` is a file descriptor that becomes readable when a
+//! process exits: a pidfd from pidfd_open(2) on Linux 5.3 and later, or
+//! else one end of a pipe written to by a SIGCHLD handler.  It can be
+//! registered with mio, or awaited under tokio.
+//!
+//! It never reaps the process, that's still up to its owner (duct,
+//! `std::process::Child`), so it can be used next to them.
+
+use filedescriptor::{FileDescriptor, Pipe};
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::io::{self, Read};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitSource {
+    Pidfd,
+    /// Readable on any SIGCHLD, not just this process's, so readiness only
+    /// means it's worth checking
+    SelfPipe,
+}
+
+/// Deregister it before dropping it: the fallback's pipe is reused, so
+/// a registration left behind could fire for another process.
+#[derive(Debug)]
+pub struct ExitNotifier {
+    pid: libc::pid_t,
+    // taken on drop, to be put back with the spare self-pipes
+    fd: ManuallyDrop<FileDescriptor>,
+    source: ExitSource,
+    // our slot in SELF_PIPES, for the fallback
+    slot: Option<usize>,
+}
+
+impl ExitNotifier {
+    /// Watch `pid`, with a pidfd if the kernel has them
+    pub fn new(pid: libc::pid_t) -> io::Result<Self> {
+        match pidfd_open(pid) {
+            Ok(fd) => Ok(Self { pid, fd: ManuallyDrop::new(fd), source: ExitSource::Pidfd, slot: None }),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => Self::with_self_pipe(pid),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Watch `pid` with the SIGCHLD fallback, even if pidfds are available
+    pub fn with_self_pipe(pid: libc::pid_t) -> io::Result<Self> {
+        install_sigchld_handler();
+        let spare = spare_pipes().pop();
+        let (slot, fd) = match spare {
+            Some(spare) => spare,
+            None => new_self_pipe()?,
+        };
+        let mut notifier = Self { pid, fd: ManuallyDrop::new(fd), source: ExitSource::SelfPipe, slot: Some(slot) };
+        // wakeups from before it was ours
+        notifier.clear();
+        Ok(notifier)
+    }
+
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    pub fn source(&self) -> ExitSource {
+        self.source
+    }
+
+    /// Whether the process has exited, without reaping it.  Only works for
+    /// our own children.
+    pub fn has_exited(&self) -> io::Result<bool> {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, self.pid as libc::id_t, &mut info, flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { info.si_pid() } != 0)
+    }
+
+    /// Consume the wakeups of the self-pipe, so that an edge triggered
+    /// registration fires again on the next SIGCHLD.  A pidfd stays readable.
+    pub fn clear(&mut self) {
+        if self.source == ExitSource::SelfPipe {
+            let mut buf = [0; 64];
+            while let Ok(n) = self.fd.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Wait under tokio until the process has exited, without reaping it
+    pub async fn wait_async(&mut self) -> io::Result<()> {
+        let stream = tokio::io::unix::AsyncFd::with_interest(self.fd.as_raw_fd(), tokio::io::Interest::READABLE)?;
+        loop {
+            if self.has_exited()? {
+                return Ok(());
+            }
+            let mut guard = stream.readable().await?;
+            self.clear();
+            guard.clear_ready();
+        }
+    }
+}
+
+impl AsRawFd for ExitNotifier {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl mio::event::Source for ExitNotifier {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+impl Drop for ExitNotifier {
+    fn drop(&mut self) {
+        // never used again
+        let fd = unsafe { ManuallyDrop::take(&mut self.fd) };
+        if let Some(slot) = self.slot {
+            spare_pipes().push((slot, fd));
+        }
+    }
+}
+
+fn pidfd_open(pid: libc::pid_t) -> io::Result<FileDescriptor> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::FromRawFd;
+        // pidfds are always close-on-exec
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { FileDescriptor::from_raw_fd(fd as RawFd) })
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pid;
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+// The write ends of the self-pipes, -1 for a free slot.  The signal handler
+// may only touch atomics and write(2).  A write end is never closed, the
+// handler may be about to write to it on another thread, so the pipes of
+// dropped notifiers are kept in SPARE_PIPES, read end and slot, for reuse.
+const SLOTS: usize = 256;
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: AtomicI32 = AtomicI32::new(-1);
+static SELF_PIPES: [AtomicI32; SLOTS] = [FREE; SLOTS];
+static SPARE_PIPES: Mutex<Vec<(usize, FileDescriptor)>> = Mutex::new(Vec::new());
+static PREVIOUS_HANDLER: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+
+fn spare_pipes() -> std::sync::MutexGuard<'static, Vec<(usize, FileDescriptor)>> {
+    SPARE_PIPES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A new pipe with its write end in a free slot, and its read end
+fn new_self_pipe() -> io::Result<(usize, FileDescriptor)> {
+    // both ends are close-on-exec
+    let mut pipe = Pipe::new().map_err(io::Error::other)?;
+    pipe.read.set_non_blocking(true).map_err(io::Error::other)?;
+    pipe.write.set_non_blocking(true).map_err(io::Error::other)?;
+    let write = pipe.write.as_raw_fd();
+    let slot = SELF_PIPES
+        .iter()
+        .position(|slot| slot.compare_exchange(-1, write, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+    match slot {
+        Some(slot) => {
+            // the slot owns it now
+            let _ = pipe.write.into_raw_fd();
+            Ok((slot, pipe.read))
+        }
+        None => Err(io::Error::other("Too many SIGCHLD self-pipes")),
+    }
+}
+
+extern "C" fn on_sigchld(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+    for slot in SELF_PIPES.iter() {
+        let fd = slot.load(Ordering::SeqCst);
+        if fd != -1 {
+            // a full pipe already has a wakeup in it
+            unsafe { libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1) };
+        }
+    }
+
+    // tokio and others have their own SIGCHLD handlers, keep them working
+    let previous = PREVIOUS_HANDLER.load(Ordering::SeqCst);
+    if previous != libc::SIG_DFL && previous != libc::SIG_IGN {
+        let previous: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+            unsafe { std::mem::transmute(previous) };
+        previous(signal, info, context);
+    }
+    unsafe { *errno_location() = errno };
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+#[cfg(any(target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno()
+}
+
+fn install_sigchld_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigchld as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_NOCLDSTOP;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut old: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGCHLD, &action, &mut old) != 0 {
+            log::error!("Failed to install a SIGCHLD handler: {:?}", io::Error::last_os_error());
+            return;
+        }
+        PREVIOUS_HANDLER.store(old.sa_sigaction, Ordering::SeqCst);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::{Events, Poll};
+    use std::time::Duration;
+
+    fn exits_after(notifier: &mut ExitNotifier, child: &mut std::process::Child) -> io::Result<()> {
+        let mut poll = Poll::new()?;
+        let mut events = Events::with_capacity(8);
+        poll.registry().register(notifier, Token(7), Interest::READABLE)?;
+        assert!(!notifier.has_exited()?);
+
+        while !notifier.has_exited()? {
+            match poll.poll(&mut events, Some(Duration::from_secs(10))) {
+                // our own SIGCHLD handler
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
+            assert!(events.iter().any(|e| e.token() == Token(7)), "no exit event");
+            notifier.clear();
+        }
+        // still ours to reap
+        assert_eq!(child.wait()?.code(), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_events() -> io::Result<()> {
+        for fallback in [false, true] {
+            let mut child = std::process::Command::new("sh").arg("-c").arg("sleep 0.2; exit 4").spawn()?;
+            let pid = child.id() as libc::pid_t;
+            let mut notifier = if fallback {
+                ExitNotifier::with_self_pipe(pid)?
+            } else {
+                ExitNotifier::new(pid)?
+            };
+            assert_eq!(notifier.source() == ExitSource::SelfPipe, fallback);
+            exits_after(&mut notifier, &mut child)?;
+        }
+
+        // on the spare pipe left by the fallback above, drained on reuse
+        let runtime = tokio::runtime::Runtime::new()?;
+        let mut child = std::process::Command::new("sh").arg("-c").arg("sleep 0.2; exit 4").spawn()?;
+        let mut notifier = ExitNotifier::with_self_pipe(child.id() as libc::pid_t)?;
+        runtime.block_on(notifier.wait_async())?;
+        assert_eq!(child.wait()?.code(), Some(4));
+        Ok(())
+    }
+}
@@ -3,26 +3,23 @@
 //! Unix-like platforms.
 //!
 //! This crate is primarily intended for pipes and other files that support
-//! nonblocking I/O.  Regular files do not support nonblocking I/O, so this
-//! crate has no effect on them.
+//! nonblocking I/O.  Regular files do not support nonblocking I/O, so IO on
+//! them goes straight to the file.
 //!
 //! See [`File`](struct.File.html) for an example of how a file can be made
 //! suitable for asynchronous I/O.
 use mio::unix::SourceFd;
-use mio::{Interest, Token, Events, Registry, event};
+use mio::{Interest, Token, Registry};
 
-use std::cell::RefCell;
+use std::io::IoSlice;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::{fs, io};
-use tokio::io::Ready;
 use tokio::io::unix::AsyncFd;
 use std::pin::Pin;
-use std::task::{Context};
+use std::task::{Context, Poll};
 use std::marker::Unpin;
- use tokio::io::ReadBuf;
-use futures::io::IoSlice;
+use tokio::io::ReadBuf;
 use tokio::io::{AsyncRead, AsyncWrite};
-use futures::StreamExt;
 
 unsafe fn dupe_file_from_fd(old_fd: RawFd) -> io::Result<fs::File> {
     let fd = libc::fcntl(old_fd, libc::F_DUPFD_CLOEXEC, 0);
@@ -95,6 +92,28 @@ pub fn set_nonblocking<F: AsRawFd>(file: &mut F, nonblocking: bool) -> io::Resul
     }
 }
 
+/// Whether `file` is a regular file, which can't be polled for readiness
+pub fn is_regular_file<F: AsRawFd>(file: &F) -> io::Result<bool> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(file.as_raw_fd(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.st_mode & libc::S_IFMT == libc::S_IFREG)
+}
+
+/// How a `File` waits for readiness under tokio
+#[derive(Debug)]
+enum Registration {
+    /// Not registered yet; that happens on the first async read or write,
+    /// so a `File` can also be used with mio or synchronously, outside a runtime
+    Unregistered,
+    Tokio(AsyncFd<RawFd>),
+    /// Regular files are always ready; epoll refuses them with EPERM.
+    /// Their IO blocks, which is what tokio::fs does too, only without
+    /// a thread pool.
+    AlwaysReady,
+}
+
 /// Wraps file-like objects for asynchronous I/O.
 ///
 /// Normally, you should use `File::new_nb` rather than `File::raw_new` unless
@@ -102,26 +121,31 @@ pub fn set_nonblocking<F: AsRawFd>(file: &mut F, nonblocking: bool) -> io::Resul
 /// Using a file descriptor that is not in nonblocking mode for asynchronous
 /// I/O will lead to subtle and confusing bugs.
 ///
-/// Wrapping regular files has no effect because they do not support
-/// nonblocking mode.
+/// Any `F: Read + Write + AsRawFd` gets `tokio::io::AsyncRead` and
+/// `AsyncWrite`, driven by readiness from the tokio reactor: pipes,
+/// sockets, ptys and ttys.  Regular files do not support nonblocking mode,
+/// they are read and written directly.
 ///
-/// The most common instantiation of this type is `File<std::fs::File>`, which
-/// indirectly provides the following trait implementation:
+/// The most common instantiation of this type is `File<std::fs::File>`.
 ///
 /// ## Example: unsafe creation from raw file descriptor
 ///
 /// To unsafely create `File<F>` from a raw file descriptor `fd`, you can do
 /// something like:
 ///
+/// ```ignore
+/// let file = File::new_nb(unsafe { std::fs::File::from_raw_fd(fd) })?;
+/// ```
+///
 /// which will enable nonblocking mode upon creation.  The choice of `F` is
 /// critical: it determines the ownership semantics of the file descriptor.
 /// For example, if you choose `F = std::fs::File`, the file descriptor will
 /// be closed when the `File` is dropped.
 #[derive(Debug)]
 pub struct File<F: AsRawFd> {
+    // declared before `file` so that it is deregistered before the fd is closed
+    registration: Registration,
     file: F,
-    //stream: AsyncFd<F>
-    //evented: RefCell<Option<mio::Registration>>,
 }
 
 impl<F: AsRawFd> File<F> {
@@ -129,7 +153,9 @@ impl<F: AsRawFd> File<F> {
     /// `tokio::io::AsyncRead` and `tokio::io::AsyncWrite`, and also *enables
     /// nonblocking mode* on the underlying file descriptor.
     pub fn new_nb(mut file: F) -> io::Result<Self> {
-        set_nonblocking(&mut file, true)?;
+        if !is_regular_file(&file)? {
+            set_nonblocking(&mut file, true)?;
+        }
         File::raw_new(file)
     }
 
@@ -138,7 +164,59 @@ impl<F: AsRawFd> File<F> {
     /// you are certain that the underlying file descriptor is already in
     /// nonblocking mode.
     pub fn raw_new(file: F) -> io::Result<Self> {
-        Ok(Self { file: file }) //, stream: AsyncFd::new(file)? })
+        let registration = if is_regular_file(&file)? {
+            Registration::AlwaysReady
+        } else {
+            Registration::Unregistered
+        };
+        Ok(Self { registration, file })
+    }
+
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    pub fn get_mut(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    /// Whether IO goes straight to the file instead of waiting for readiness
+    pub fn is_always_ready(&self) -> bool {
+        matches!(self.registration, Registration::AlwaysReady)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+}
+
+/// Register `fd` with tokio if it isn't yet.  None means it's always ready.
+fn async_fd(registration: &mut Registration, fd: RawFd) -> io::Result<Option<&AsyncFd<RawFd>>> {
+    if let Registration::Unregistered = registration {
+        *registration = match AsyncFd::new(fd) {
+            Ok(stream) => Registration::Tokio(stream),
+            // not a regular file by fstat, but epoll still won't have it
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => Registration::AlwaysReady,
+            Err(e) => return Err(e),
+        };
+    }
+    match registration {
+        Registration::Tokio(stream) => Ok(Some(stream)),
+        _ => Ok(None),
+    }
+}
+
+/// Read, treating EIO as EOF
+fn read_file<F: io::Read>(file: &mut F, buf: &mut [u8]) -> io::Result<usize> {
+    match file.read(buf) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EIO) => {
+            // EIO indicates that the slave pty has been closed.
+            // Treat this as EOF so that std::io::Read::read_to_string
+            // and similar functions gracefully terminate when they
+            // encounter this condition
+            Ok(0)
+        }
+        x => x,
     }
 }
 
@@ -148,6 +226,8 @@ impl<F: AsRawFd> AsRawFd for File<F> {
     }
 }
 
+/// For use with mio directly.  Registering a regular file fails with EPERM,
+/// mio has no way to make it always ready.
 impl<F: AsRawFd> mio::event::Source for File<F> {
     fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
         -> io::Result<()>
@@ -166,46 +246,9 @@ impl<F: AsRawFd> mio::event::Source for File<F> {
     }
 }
 
-
-//impl<F: AsRawFd> mio::event::Source for File<F> {
-    //fn register(
-        //&mut self,
-        //registry: &mio::Registry,
-        //token: mio::Token,
-        //interest: mio::Interest,
-    //) -> io::Result<()> {
-        //match mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interest) {
-            //// this is a workaround for regular files, which are not supported
-            //// by epoll; they would instead cause EPERM upon registration
-            //Err(e) => e,
-            ////Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => {
-                ////set_nonblocking(&mut self.as_raw_fd(), false)?;
-                ////// workaround: PollEvented/IoToken always starts off in the
-                ////// "not ready" state so we have to use a real Evented object
-                ////// to set its readiness state
-                ////let (r, s) = mio::Registration::new2();
-                ////r.register(poll, token, interest)?;
-                ////s.set_readiness(Ready::readable() | Ready::writable())?;
-                ///[>self.evented.borrow_mut() = Some(r);
-                ////Ok(())
-            ////}
-            //e => e,
-        //}
-    //}
-
 impl<F: io::Read + AsRawFd> io::Read for File<F> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.file.read(buf) {
-            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => {
-                // EIO indicates that the slave pty has been closed.
-                // Treat this as EOF so that std::io::Read::read_to_string
-                // and similar functions gracefully terminate when they
-                // encounter this condition
-                Ok(0)
-            }
-            x => x,
-        }
-        //self.file.read(buf)
+        read_file(&mut self.file, buf)
     }
 }
 
@@ -225,43 +268,105 @@ impl<F: io::Seek + AsRawFd> io::Seek for File<F> {
     }
 }
 
-impl <F: AsyncRead + Unpin + AsRawFd> AsyncRead for File<F> {
-    #[inline]
+impl<F: io::Read + AsRawFd + Unpin> AsyncRead for File<F> {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf,
-    ) -> std::task::Poll<io::Result<()>> {
-        Pin::new(&mut self.file).poll_read(cx, buf)
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let fd = this.file.as_raw_fd();
+        let stream = match async_fd(&mut this.registration, fd)? {
+            Some(stream) => stream,
+            None => {
+                let n = read_file(&mut this.file, buf.initialize_unfilled())?;
+                buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+        };
+
+        loop {
+            let mut guard = match stream.poll_read_ready(cx) {
+                Poll::Ready(r) => r?,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|_| read_file(&mut this.file, unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // spurious readiness, clear it and wait again
+                Err(_would_block) => continue,
+            }
+        }
     }
 }
 
-impl<F: AsyncWrite + Unpin + AsRawFd> AsyncWrite for File<F> {
-    #[inline]
+impl<F: io::Write + AsRawFd + Unpin> AsyncWrite for File<F> {
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
-    ) -> std::task::Poll<io::Result<usize>> {
-        Pin::new(&mut self.file).poll_write(cx, buf)
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let fd = this.file.as_raw_fd();
+        let stream = match async_fd(&mut this.registration, fd)? {
+            Some(stream) => stream,
+            None => return Poll::Ready(io::Write::write(&mut this.file, buf)),
+        };
+
+        loop {
+            let mut guard = match stream.poll_write_ready(cx) {
+                Poll::Ready(r) => r?,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match guard.try_io(|_| io::Write::write(&mut this.file, buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
     }
 
     fn poll_write_vectored(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         bufs: &[IoSlice<'_>],
-    ) -> std::task::Poll<io::Result<usize>> {
-        Pin::new(&mut self.file).poll_write_vectored(cx, bufs)
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let fd = this.file.as_raw_fd();
+        let stream = match async_fd(&mut this.registration, fd)? {
+            Some(stream) => stream,
+            None => return Poll::Ready(io::Write::write_vectored(&mut this.file, bufs)),
+        };
+
+        loop {
+            let mut guard = match stream.poll_write_ready(cx) {
+                Poll::Ready(r) => r?,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match guard.try_io(|_| io::Write::write_vectored(&mut this.file, bufs)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
     }
 
-    #[inline]
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> std::task::Poll<io::Result<()>> {
-        Pin::new(&mut self.file).poll_flush(cx)
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(io::Write::flush(&mut self.get_mut().file))
     }
 
     #[inline]
-    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> std::task::Poll<io::Result<()>> {
-        Pin::new(&mut self.file).poll_shutdown(cx)
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -282,4 +387,33 @@ mod tests {
         assert!(!get_nonblocking(&fd)?);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_async_io() -> io::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (a, b) = UnixStream::pair()?;
+        let mut a = File::new_nb(a)?;
+        let mut b = File::new_nb(b)?;
+        assert!(get_nonblocking(&a)? && !a.is_always_ready());
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![];
+            b.read_to_end(&mut buf).await.map(|_| buf)
+        });
+        // more than the socket buffer, so the writer has to wait for the reader
+        let data = vec![7u8; 1 << 20];
+        a.write_all(&data).await?;
+        drop(a);
+        assert_eq!(reader.await.unwrap()?, data);
+
+        let path = std::env::temp_dir().join(format!("fd-test-{}", std::process::id()));
+        let mut file = File::new_nb(fs::File::create(&path)?)?;
+        assert!(file.is_always_ready());
+        file.write_all(b"regular").await?;
+        let mut contents = String::new();
+        File::new_nb(fs::File::open(&path)?)?.read_to_string(&mut contents).await?;
+        fs::remove_file(&path)?;
+        assert_eq!(contents, "regular");
+        Ok(())
+    }
 }
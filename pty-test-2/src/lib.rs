@@ -1,15 +1,73 @@
 mod fd;
+mod backend;
+mod reactor;
+mod exit;
+mod pipeline;
 
 pub use fd::*;
+pub use backend::*;
+pub use reactor::*;
+pub use exit::*;
+pub use pipeline::*;
+pub use pty_test::PtySize;
 
-use futures::prelude::*;
-use std::io::{Read, Write};
-use mio::{Poll, Interest, Token, Events, Registry, event};
-use mio::unix::SourceFd;
+use filedescriptor::FileDescriptor;
 use std::io;
-use std::os::unix::io::{RawFd, AsRawFd};
-use tokio::io::AsyncReadExt;
-use tokio_serde::formats::*;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use serde::{Serialize, Deserialize};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::ptr;
 
+pub fn openpty(size: PtySize) -> Result<(FileDescriptor, FileDescriptor), failure::Error> {
+    let mut master: RawFd = -1;
+    let mut slave: RawFd = -1;
+
+    let mut size: libc::winsize = size.into();
+
+    let result = unsafe {
+        // BSDish systems may require mut pointers to some args
+        #[cfg_attr(feature = "cargo-clippy", allow(clippy::unnecessary_mut_passed))]
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut size,
+        )
+    };
+
+    log::info!("open: {:?}", (master, slave));
+
+    if result != 0 {
+        log::error!("Failed to openpty: {:?}", io::Error::last_os_error());
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let master = unsafe {FileDescriptor::from_raw_fd(master)};
+    let slave = unsafe {FileDescriptor::from_raw_fd(slave)};
+
+    // children get the slave as stdio, not as a stray fd that keeps it open
+    set_cloexec(&master)?;
+    set_cloexec(&slave)?;
+
+    Ok((master, slave))
+}
+
+fn set_cloexec(fd: &FileDescriptor) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Open a pty with posix_openpt(3), returning the blocking master and the
+/// path of the slave, which isn't opened.  Give the path to duct with
+/// `stdin_path` and friends, or to a program that opens the tty itself.
+pub fn openpt(size: PtySize) -> Result<(FileDescriptor, PathBuf), failure::Error> {
+    let master = pty_test::openpt_fd(size)?;
+    let path = pty_test::ptsname_fd(master.as_raw_fd())?;
+    log::info!("openpt: {:?}", (master.as_raw_fd(), &path));
+    Ok((master, path))
+}
@@ -0,0 +1,295 @@
+use filedescriptor::{FileDescriptor, Pipe};
+use pty_test::{PtyExitStatus, PtySize};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use crate::backend::take_controlling_tty;
+use crate::openpty;
+
+/// A pipeline of duct expressions, `a | b | c`, with pipes between the
+/// stages and one stage on a pty, like a shell runs a pipeline in a terminal.
+///
+/// The tty stage gets the pty for its stderr, and for its stdin or stdout
+/// if it's first or last.  It's made a session leader with the pty as its
+/// controlling terminal.  Stdio of the other stages that isn't a pipe is
+/// theirs or ours, and redirections inside a stage's expression win over
+/// the pipes.
+///
+/// Unlike in a shell, signals from the pty, like ^C and ^Z, only reach the
+/// tty stage.  A shell puts every stage in one process group and makes it
+/// the terminal's foreground group, but it's the session leader and the
+/// stages are its children.  Here they're our children and in our session,
+/// and a process group can't span sessions.  Signal the whole pipeline
+/// with `PipelineHandle::signal` instead.
+pub struct PtyPipeline {
+    stages: Vec<duct::Expression>,
+    tty: usize,
+}
+
+impl PtyPipeline {
+    /// The last stage gets the tty, see `tty_stage`
+    pub fn new<I>(stages: I) -> Self
+    where
+        I: IntoIterator<Item = duct::Expression>,
+    {
+        let stages = stages.into_iter().collect::<Vec<_>>();
+        let tty = stages.len().saturating_sub(1);
+        Self { stages, tty }
+    }
+
+    /// Put stage `stage`, counting from 0, on the pty
+    pub fn tty_stage(&mut self, stage: usize) -> &mut Self {
+        self.tty = stage;
+        self
+    }
+
+    /// Open a pty of `size` and start every stage
+    pub fn start(&self, size: PtySize) -> Result<PipelineHandle, failure::Error> {
+        if self.tty >= self.stages.len() {
+            return Err(failure::format_err!("No stage {} in a pipeline of {}", self.tty, self.stages.len()));
+        }
+
+        let (master, slave) = openpty(size)?;
+        let slave_fd = slave.as_raw_fd();
+        let mut handles = vec![];
+        // the read end of the pipe from the previous stage
+        let mut previous: Option<FileDescriptor> = None;
+
+        for (i, stage) in self.stages.iter().enumerate() {
+            let first = i == 0;
+            let last = i + 1 == self.stages.len();
+            let mut e = stage.unchecked();
+
+            if let Some(read) = previous.take() {
+                e = e.stdin_file(read);
+            }
+            if !last {
+                let pipe = Pipe::new()?;
+                e = e.stdout_file(pipe.write);
+                previous = Some(pipe.read);
+            }
+            if i == self.tty {
+                if first {
+                    e = e.stdin_file(slave.try_clone()?);
+                }
+                if last {
+                    e = e.stdout_file(slave.try_clone()?);
+                }
+                e = e.stderr_file(slave.try_clone()?).before_spawn(move |cmd| {
+                    unsafe {
+                        cmd.pre_exec(move || take_controlling_tty(slave_fd));
+                    }
+                    Ok(())
+                });
+            }
+
+            // `e` holds our ends of its pipes, dropped once it's started
+            match e.start() {
+                Ok(handle) => handles.push(handle),
+                Err(err) => {
+                    log::error!("Failed to start stage {} of a pipeline: {:?}", i, err);
+                    for handle in &handles {
+                        let _ = handle.kill();
+                        let _ = handle.wait();
+                    }
+                    return Err(err.into());
+                }
+            }
+        }
+        drop(slave);
+
+        Ok(PipelineHandle { master, handles, tty: self.tty })
+    }
+}
+
+/// The stages of a started `PtyPipeline` and the pty master.  Stages that
+/// are still running are killed on drop.
+pub struct PipelineHandle {
+    master: FileDescriptor,
+    handles: Vec<duct::Handle>,
+    tty: usize,
+}
+
+impl PipelineHandle {
+    pub fn master(&self) -> &FileDescriptor {
+        &self.master
+    }
+
+    /// A handle per stage, in order
+    pub fn handles(&self) -> &[duct::Handle] {
+        &self.handles
+    }
+
+    /// The index of the stage on the pty
+    pub fn tty_stage(&self) -> usize {
+        self.tty
+    }
+
+    /// The pids of all stages, in order
+    pub fn pids(&self) -> Vec<u32> {
+        self.handles.iter().flat_map(|h| h.pids()).collect()
+    }
+
+    /// Read output from the master, 0 once the tty stage has closed the pty
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.master.read(buf) {
+            // EIO means the slave has been closed
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            x => x,
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    pub fn resize(&mut self, size: PtySize) -> Result<(), failure::Error> {
+        pty_test::resize_fd(self.master.as_raw_fd(), size)
+    }
+
+    /// The statuses of all stages, if all of them have exited
+    pub fn try_wait(&self) -> io::Result<Option<PipelineStatus>> {
+        let mut stages = vec![];
+        for handle in &self.handles {
+            match handle.try_wait()? {
+                Some(output) => stages.push(output.status.into()),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(PipelineStatus { stages }))
+    }
+
+    pub fn wait(&self) -> io::Result<PipelineStatus> {
+        let mut stages = vec![];
+        for handle in &self.handles {
+            stages.push(handle.wait()?.status.into());
+        }
+        Ok(PipelineStatus { stages })
+    }
+
+    /// Send `signal` to every stage, as ^C would in a shell's terminal
+    pub fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+        for pid in self.pids() {
+            if unsafe { libc::kill(pid as libc::pid_t, signal) } == -1 {
+                let err = io::Error::last_os_error();
+                // exited, but not reaped yet
+                if err.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn kill(&self) -> io::Result<()> {
+        for handle in &self.handles {
+            handle.kill()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipelineHandle {
+    fn drop(&mut self) {
+        for handle in &self.handles {
+            if let Ok(None) = handle.try_wait() {
+                let _ = handle.kill();
+            }
+        }
+    }
+}
+
+/// How each stage of a pipeline exited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineStatus {
+    /// One per stage, in order
+    pub stages: Vec<PtyExitStatus>,
+}
+
+impl PipelineStatus {
+    /// What a shell reports for the pipeline, the status of the last stage
+    pub fn status(&self) -> PtyExitStatus {
+        self.stages.last().copied().unwrap_or_else(|| PtyExitStatus::exited(0))
+    }
+
+    /// The status with `set -o pipefail`: that of the last stage that failed
+    pub fn pipefail(&self) -> PtyExitStatus {
+        self.stages.iter().rev().find(|s| !s.success()).copied().unwrap_or_else(|| self.status())
+    }
+
+    /// Whether every stage succeeded
+    pub fn success(&self) -> bool {
+        self.stages.iter().all(|s| s.success())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_to_end(pipeline: &mut PipelineHandle) -> String {
+        let mut out = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            match pipeline.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_pipeline() -> Result<(), failure::Error> {
+        // the usual case, the last stage on the pty
+        let mut pipeline = PtyPipeline::new(vec![
+            duct::cmd!("sh", "-c", "echo one; echo two; exit 2"),
+            duct::cmd!("tr", "a-z", "A-Z"),
+            duct::cmd!("sh", "-c", "cat; tty -s </dev/tty && echo ctty; exit 5"),
+        ])
+        .start(PtySize::default())?;
+        assert_eq!(pipeline.pids().len(), 3);
+        assert_eq!(read_to_end(&mut pipeline), "ONE\r\nTWO\r\nctty\r\n");
+        let status = pipeline.wait()?;
+        let codes = status.stages.iter().map(|s| s.code).collect::<Vec<_>>();
+        assert_eq!(codes, vec![Some(2), Some(0), Some(5)]);
+        assert_eq!(status.status().code, Some(5));
+
+        // the first stage on the pty, the rest of the output captured
+        let mut pipeline = PtyPipeline::new(vec![
+            duct::cmd!("sh", "-c", "tty; echo oops >&2; exit 1"),
+            duct::cmd!("tr", "a-z", "A-Z").stdout_capture(),
+        ])
+        .tty_stage(0)
+        .start(PtySize::default())?;
+        assert_eq!(read_to_end(&mut pipeline), "oops\r\n");
+        let status = pipeline.wait()?;
+        assert!(status.status().success() && !status.success());
+        assert_eq!(status.pipefail().code, Some(1));
+        let captured = String::from_utf8(pipeline.handles()[1].wait()?.stdout.clone())?;
+        assert!(captured.starts_with("/DEV/PTS/"), "{:?}", captured);
+        Ok(())
+    }
+
+    #[test]
+    fn test_signal_and_failed_start() -> Result<(), failure::Error> {
+        let mut pipeline = PtyPipeline::new(vec![
+            duct::cmd!("sleep", "30"),
+            duct::cmd!("sh", "-c", "echo ready; exec cat"),
+        ])
+        .start(PtySize::default())?;
+        let mut buf = [0; 64];
+        assert!(pipeline.read(&mut buf)? > 0);
+        pipeline.signal(libc::SIGTERM)?;
+        let status = pipeline.wait()?;
+        assert!(status.stages.iter().all(|s| s.signal == Some(libc::SIGTERM)), "{:?}", status);
+
+        // the stages already started are killed and reaped
+        let err = PtyPipeline::new(vec![duct::cmd!("sleep", "30"), duct::cmd!("/nonexistent")])
+            .start(PtySize::default())
+            .err()
+            .expect("a missing program");
+        assert!(err.downcast_ref::<io::Error>().is_some(), "{}", err);
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+use filedescriptor::FileDescriptor;
+use mio::{Events, Interest, Poll, Token};
+use pty_test::{Pty, PtyCommandBuilder, PtyExitStatus, PtySize};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use crate::backend::DuctPty;
+use crate::exit::ExitNotifier;
+use crate::fd::File;
+
+pub type ChildId = usize;
+
+/// A process watched by the `Reactor`
+pub trait ReactorChild {
+    /// The exit status, if the process has exited, without blocking
+    fn try_wait(&mut self) -> io::Result<Option<PtyExitStatus>>;
+    fn kill(&mut self) -> io::Result<()>;
+
+    /// The pid to watch for exit.  Without one the child is polled.
+    fn pid(&self) -> Option<libc::pid_t> {
+        None
+    }
+}
+
+impl ReactorChild for duct::Handle {
+    fn try_wait(&mut self) -> io::Result<Option<PtyExitStatus>> {
+        Ok(duct::Handle::try_wait(self)?.map(|output| output.status.into()))
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        duct::Handle::kill(self)
+    }
+
+    fn pid(&self) -> Option<libc::pid_t> {
+        self.pids().first().map(|pid| *pid as libc::pid_t)
+    }
+}
+
+/// Callbacks for the events of one child and its sources
+pub trait ChildHandler {
+    /// Output read from `source`
+    fn output(&mut self, io: &mut ChildIo, source: Token, data: &[u8]);
+
+    /// `source` reached EOF or hung up, and has been removed
+    fn hangup(&mut self, _io: &mut ChildIo, _source: Token) {}
+
+    /// The child exited, after the output left in its sources was delivered
+    fn exited(&mut self, status: PtyExitStatus);
+}
+
+/// What a handler wants done with the sources of its child, applied by
+/// the reactor after the callback returns
+#[derive(Debug, Default)]
+pub struct ChildIo {
+    writes: Vec<(Token, Vec<u8>)>,
+    closes: Vec<Token>,
+}
+
+impl ChildIo {
+    /// Queue `data` to be written to `source`
+    pub fn write(&mut self, source: Token, data: &[u8]) {
+        self.writes.push((source, data.to_vec()));
+    }
+
+    /// Close `source`, for example to send EOF on a stdin pipe
+    pub fn close(&mut self, source: Token) {
+        self.closes.push(source);
+    }
+}
+
+struct SourceEntry {
+    child: ChildId,
+    file: File<FileDescriptor>,
+    pending: Vec<u8>,
+}
+
+struct ChildEntry {
+    child: Box<dyn ReactorChild>,
+    handler: Box<dyn ChildHandler>,
+    sources: Vec<Token>,
+    exit: Option<(Token, ExitNotifier)>,
+}
+
+/// Drives many children and their pty masters and pipes from one thread.
+///
+/// Sources are registered edge triggered, for both reading and writing.
+/// On every turn the reactor reads each ready source until it would block,
+/// flushes queued writes, and checks the children that may have exited; an
+/// exited child's remaining output is delivered and its sources are removed.
+///
+/// Each child with a pid gets an `ExitNotifier` in the same poll, so its
+/// exit wakes the reactor like output does.  Other children are polled.
+pub struct Reactor {
+    poll: Poll,
+    events: Events,
+    sources: HashMap<Token, SourceEntry>,
+    children: HashMap<ChildId, ChildEntry>,
+    exits: HashMap<Token, ChildId>,
+    // children whose exit notifier fired, or that were just added
+    check: Vec<ChildId>,
+    next_token: usize,
+    next_child: ChildId,
+    buf: Vec<u8>,
+}
+
+impl Reactor {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            poll: Poll::new()?,
+            events: Events::with_capacity(1024),
+            sources: HashMap::new(),
+            children: HashMap::new(),
+            exits: HashMap::new(),
+            check: vec![],
+            next_token: 0,
+            next_child: 0,
+            buf: vec![0; 8192],
+        })
+    }
+
+    pub fn add_child<C, H>(&mut self, child: C, handler: H) -> ChildId
+    where
+        C: ReactorChild + 'static,
+        H: ChildHandler + 'static,
+    {
+        let id = self.next_child;
+        self.next_child += 1;
+        let exit = child.pid().and_then(|pid| match self.watch_exit(id, pid) {
+            Ok(exit) => Some(exit),
+            Err(e) => {
+                log::error!("Failed to watch child {} for exit, polling it: {:?}", id, e);
+                None
+            }
+        });
+        self.children.insert(id, ChildEntry {
+            child: Box::new(child),
+            handler: Box::new(handler),
+            sources: vec![],
+            exit,
+        });
+        // it may have exited before the notifier was registered
+        self.check.push(id);
+        id
+    }
+
+    fn watch_exit(&mut self, child: ChildId, pid: libc::pid_t) -> io::Result<(Token, ExitNotifier)> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        let mut notifier = ExitNotifier::new(pid)?;
+        self.poll.registry().register(&mut notifier, token, Interest::READABLE)?;
+        self.exits.insert(token, child);
+        Ok((token, notifier))
+    }
+
+    /// Watch `fd`, a pty master or pipe of `child`.  It's put in nonblocking mode.
+    pub fn add_source(&mut self, child: ChildId, fd: FileDescriptor) -> io::Result<Token> {
+        let entry = self.children.get_mut(&child).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No child {}", child))
+        })?;
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        let mut file = File::new_nb(fd)?;
+        self.poll.registry().register(&mut file, token, Interest::READABLE | Interest::WRITABLE)?;
+        entry.sources.push(token);
+        self.sources.insert(token, SourceEntry { child, file, pending: vec![] });
+        Ok(token)
+    }
+
+    /// Spawn `command` on a new pty with the duct backend, and watch its master
+    pub fn spawn_pty<H>(&mut self, command: &PtyCommandBuilder, size: PtySize, handler: H)
+        -> Result<(ChildId, Token), failure::Error>
+    where
+        H: ChildHandler + 'static,
+    {
+        let (master, handle) = DuctPty::spawn(command, size)?.into_parts();
+        let child = self.add_child(handle, handler);
+        let token = self.add_source(child, master)?;
+        Ok((child, token))
+    }
+
+    /// Write `data` to `source`, as much as fits now; the rest is written
+    /// when the source becomes writable
+    pub fn write(&mut self, source: Token, data: &[u8]) -> io::Result<()> {
+        let entry = self.sources.get_mut(&source).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No source {:?}", source))
+        })?;
+        entry.pending.extend_from_slice(data);
+        flush(entry)
+    }
+
+    pub fn kill(&mut self, child: ChildId) -> io::Result<()> {
+        match self.children.get_mut(&child) {
+            Some(entry) => entry.child.kill(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn source_count(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Wait up to `timeout` for events and dispatch them
+    pub fn turn(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.poll.poll(&mut self.events, timeout) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            result => result?,
+        }
+
+        let ready = self
+            .events
+            .iter()
+            .map(|event| (event.token(), event.is_readable() || event.is_read_closed(), event.is_writable()))
+            .collect::<Vec<_>>();
+
+        for (token, readable, writable) in ready {
+            if let Some(child) = self.exits.get(&token) {
+                if let Some((_, notifier)) = self.children.get_mut(child).and_then(|c| c.exit.as_mut()) {
+                    notifier.clear();
+                }
+                self.check.push(*child);
+                continue;
+            }
+            if writable {
+                if let Some(entry) = self.sources.get_mut(&token) {
+                    if let Err(e) = flush(entry) {
+                        log::error!("Failed to write to {:?}: {:?}", token, e);
+                        entry.pending.clear();
+                    }
+                }
+            }
+            if readable {
+                self.drain(token);
+            }
+        }
+
+        self.reap();
+        Ok(())
+    }
+
+    /// Dispatch events until every child has exited.  Children without an
+    /// exit notifier are polled every `interval`.
+    pub fn run(&mut self, interval: Duration) -> io::Result<()> {
+        while !self.children.is_empty() {
+            let polled = self.children.values().any(|c| c.exit.is_none());
+            self.turn(if polled { Some(interval) } else { None })?;
+        }
+        Ok(())
+    }
+
+    /// Read `token` until it would block, handing the output to its child's
+    /// handler, and remove it at EOF
+    fn drain(&mut self, token: Token) {
+        let mut io = ChildIo::default();
+        while let Some(entry) = self.sources.get_mut(&token) {
+            let child = entry.child;
+            let n = match entry.file.read(&mut self.buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::error!("Failed to read from {:?}: {:?}", token, e);
+                    0
+                }
+            };
+
+            if n == 0 {
+                self.remove_source(token);
+                if let Some(entry) = self.children.get_mut(&child) {
+                    entry.handler.hangup(&mut io, token);
+                }
+                break;
+            }
+            if let Some(entry) = self.children.get_mut(&child) {
+                entry.handler.output(&mut io, token, &self.buf[..n]);
+            }
+        }
+        self.apply(io);
+    }
+
+    fn apply(&mut self, io: ChildIo) {
+        for (token, data) in io.writes {
+            if let Err(e) = self.write(token, &data) {
+                log::error!("Failed to write to {:?}: {:?}", token, e);
+            }
+        }
+        for token in io.closes {
+            self.remove_source(token);
+        }
+    }
+
+    fn remove_source(&mut self, token: Token) {
+        if let Some(mut entry) = self.sources.remove(&token) {
+            if let Err(e) = self.poll.registry().deregister(&mut entry.file) {
+                log::error!("Failed to deregister {:?}: {:?}", token, e);
+            }
+            if let Some(child) = self.children.get_mut(&entry.child) {
+                child.sources.retain(|t| *t != token);
+            }
+        }
+    }
+
+    /// Tear down the children that have exited
+    fn reap(&mut self) {
+        let mut check = std::mem::take(&mut self.check);
+        check.extend(self.children.iter().filter(|(_, c)| c.exit.is_none()).map(|(id, _)| *id));
+        check.sort_unstable();
+        check.dedup();
+
+        let mut exited = vec![];
+        for id in check {
+            let entry = match self.children.get_mut(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match entry.child.try_wait() {
+                Ok(Some(status)) => exited.push((id, status)),
+                Ok(None) => (),
+                Err(e) => {
+                    log::error!("Failed to wait for child {}: {:?}", id, e);
+                    exited.push((id, PtyExitStatus::exited(-1)));
+                }
+            }
+        }
+
+        for (id, status) in exited {
+            let sources = self.children.get(&id).map(|c| c.sources.clone()).unwrap_or_default();
+            for token in sources {
+                self.drain(token);
+                self.remove_source(token);
+            }
+            if let Some(mut entry) = self.children.remove(&id) {
+                if let Some((token, mut notifier)) = entry.exit.take() {
+                    self.exits.remove(&token);
+                    if let Err(e) = self.poll.registry().deregister(&mut notifier) {
+                        log::error!("Failed to deregister {:?}: {:?}", token, e);
+                    }
+                }
+                log::info!("child {} exited: {:?}", id, status);
+                entry.handler.exited(status);
+            }
+        }
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        for entry in self.children.values_mut() {
+            if let Ok(None) = entry.child.try_wait() {
+                let _ = entry.child.kill();
+            }
+        }
+    }
+}
+
+/// Write as much of the pending data as the source takes
+fn flush(entry: &mut SourceEntry) -> io::Result<()> {
+    while !entry.pending.is_empty() {
+        match entry.file.write(&entry.pending) {
+            Ok(n) => {
+                entry.pending.drain(..n);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Results {
+        output: HashMap<usize, Vec<u8>>,
+        statuses: HashMap<usize, PtyExitStatus>,
+    }
+
+    struct Collect {
+        n: usize,
+        results: Rc<RefCell<Results>>,
+    }
+
+    impl ChildHandler for Collect {
+        fn output(&mut self, io: &mut ChildIo, source: Token, data: &[u8]) {
+            let mut results = self.results.borrow_mut();
+            let output = results.output.entry(self.n).or_default();
+            output.extend_from_slice(data);
+            if output.ends_with(b"ready\r\n") {
+                io.write(source, b"bye\n");
+            }
+        }
+
+        fn exited(&mut self, status: PtyExitStatus) {
+            self.results.borrow_mut().statuses.insert(self.n, status);
+        }
+    }
+
+    #[test]
+    fn test_many_children() -> Result<(), failure::Error> {
+        let results = Rc::new(RefCell::new(Results::default()));
+        let mut reactor = Reactor::new()?;
+        for n in 0..20 {
+            let mut command = PtyCommandBuilder::new("sh");
+            command.arg("-c").arg(format!("echo ready; read x; echo $x {}; exit {}", n, n));
+            reactor.spawn_pty(&command, PtySize::default(), Collect { n, results: results.clone() })?;
+        }
+        assert_eq!(reactor.source_count(), 20);
+        // every child has an exit notifier, a missed exit leaves it
+        // unreaped rather than caught by polling
+        let deadline = std::time::Instant::now() + Duration::from_secs(20);
+        while reactor.child_count() > 0 {
+            assert!(std::time::Instant::now() < deadline, "{} children never reaped", reactor.child_count());
+            reactor.turn(Some(Duration::from_millis(100)))?;
+        }
+        assert_eq!(reactor.source_count(), 0);
+
+        let results = results.borrow();
+        for n in 0..20 {
+            let output = String::from_utf8_lossy(&results.output[&n]).into_owned();
+            assert!(output.ends_with(&format!("bye {}\r\n", n)), "{:?}", output);
+            assert_eq!(results.statuses[&n].code, Some(n as i32));
+        }
+        Ok(())
+    }
+}
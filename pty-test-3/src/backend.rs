@@ -0,0 +1,252 @@
+use pty::fork::{Fork, Master};
+use pty_test::{Pty, PtyCommandBuilder, PtyExitStatus, PtySize};
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// The `pty::fork` backend.
+///
+/// `Fork` forks first and leaves the child to run, so everything the child
+/// needs is prepared beforehand and the child only makes async-signal-safe
+/// calls before exec.  The child is killed when this is dropped.
+pub struct ForkPty {
+    // owns the master, and closes it on drop
+    fork: Fork,
+    master: Master,
+    pid: libc::pid_t,
+    status: Option<PtyExitStatus>,
+}
+
+fn cstring<S: AsRef<OsStr>>(s: S) -> Result<CString, failure::Error> {
+    Ok(CString::new(s.as_ref().as_bytes())?)
+}
+
+/// Find `program` on `path` the way execvp(3) would
+fn find_program(program: &OsStr, path: Option<&OsStr>) -> Option<PathBuf> {
+    if program.as_bytes().contains(&b'/') {
+        return Some(program.into());
+    }
+    std::env::split_paths(path?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+fn is_executable(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(m) => m.is_file() && m.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+impl ForkPty {
+    pub fn master(&self) -> &Master {
+        &self.master
+    }
+
+    pub fn id(&self) -> libc::pid_t {
+        self.pid
+    }
+}
+
+impl Pty for ForkPty {
+    /// Only with all stdio on the pty
+    fn supports(command: &PtyCommandBuilder) -> bool {
+        command.stdio_is_pty()
+    }
+
+    fn spawn(command: &PtyCommandBuilder, size: PtySize) -> Result<Self, failure::Error> {
+        if !Self::supports(command) {
+            return Err(failure::err_msg("The fork backend only runs programs with all stdio on the pty"));
+        }
+
+        let mut env: BTreeMap<OsString, OsString> = if command.get_env_clear() {
+            BTreeMap::new()
+        } else {
+            std::env::vars_os().collect()
+        };
+        for (key, value) in command.get_envs() {
+            match value {
+                Some(value) => env.insert(key, value),
+                None => env.remove(&key),
+            };
+        }
+
+        let program = find_program(command.get_program(), env.get(OsStr::new("PATH")).map(|p| p.as_os_str()))
+            .ok_or_else(|| failure::format_err!("{:?} not found", command.get_program()))?;
+        let program = cstring(program)?;
+
+        let argv0 = command.get_argv0().unwrap_or_else(|| command.get_program());
+        let args = std::iter::once(argv0)
+            .chain(command.get_args().iter().map(|a| a.as_os_str()))
+            .map(cstring)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut argv: Vec<*const libc::c_char> = args.iter().map(|a| a.as_ptr()).collect();
+        argv.push(std::ptr::null());
+
+        let vars = env
+            .iter()
+            .map(|(k, v)| {
+                let mut var = k.clone();
+                var.push("=");
+                var.push(v);
+                cstring(var)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut envp: Vec<*const libc::c_char> = vars.iter().map(|v| v.as_ptr()).collect();
+        envp.push(std::ptr::null());
+
+        let cwd = command.get_cwd().map(cstring).transpose()?;
+        let mut pre_exec = command.get_pre_exec().prepare()?;
+
+        // The child waits for a byte on this pipe, so that the pty has its
+        // size before the program can ask for it.  Not for EOF: a child
+        // forked by another thread meanwhile may hold the write end too.
+        let mut ready = [-1; 2];
+        if unsafe { libc::pipe2(ready.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let parent = unsafe { libc::getpid() };
+        let fork = Fork::from_ptmx();
+        if unsafe { libc::getpid() } != parent {
+            // Fork has set up the slave as stdio and controlling terminal,
+            // or failed to and returned an error in the child too
+            unsafe {
+                if fork.is_err() {
+                    libc::_exit(127);
+                }
+                libc::close(ready[1]);
+                let mut byte = 0u8;
+                loop {
+                    match libc::read(ready[0], &mut byte as *mut u8 as *mut _, 1) {
+                        1 => break,
+                        -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+                        // the parent gave up on us
+                        _ => libc::_exit(127),
+                    }
+                }
+                if let Some(cwd) = &cwd {
+                    if libc::chdir(cwd.as_ptr()) == -1 {
+                        libc::_exit(127);
+                    }
+                }
+                if pre_exec.apply().is_err() {
+                    libc::_exit(127);
+                }
+                libc::execve(program.as_ptr(), argv.as_ptr(), envp.as_ptr());
+                libc::_exit(127);
+            }
+        }
+
+        unsafe {
+            libc::close(ready[0]);
+        }
+        let result = fork.map_err(failure::Error::from).and_then(|fork| {
+            let pid = match fork {
+                Fork::Parent(pid, _) => pid,
+                Fork::Child(_) => unreachable!(),
+            };
+            let master = fork.is_parent()?;
+            let pty = Self { fork, master, pid, status: None };
+            pty_test::resize_fd(pty.master.as_raw_fd(), size)?;
+            if unsafe { libc::write(ready[1], b"r".as_ptr() as *const _, 1) } != 1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(pty)
+        });
+        // on error, the ForkPty that was built kills and reaps the child
+        // when it's dropped
+        unsafe {
+            libc::close(ready[1]);
+        }
+        result
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Master turns every error into EOF, including EIO once the slave is closed
+        self.master.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn resize(&mut self, size: PtySize) -> Result<(), failure::Error> {
+        pty_test::resize_fd(self.master.as_raw_fd(), size)
+    }
+
+    fn signal(&mut self, signal: libc::c_int) -> Result<(), failure::Error> {
+        if self.status.is_some() {
+            return Err(failure::err_msg("Child has already been reaped"));
+        }
+        if unsafe { libc::kill(self.pid, signal) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<PtyExitStatus, failure::Error> {
+        // Fork::wait throws the status away
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        let mut status = 0;
+        loop {
+            if unsafe { libc::waitpid(self.pid, &mut status, 0) } != -1 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+        let status = PtyExitStatus::from_raw(status);
+        self.status = Some(status);
+        Ok(status)
+    }
+}
+
+impl Drop for ForkPty {
+    fn drop(&mut self) {
+        if self.status.is_none() {
+            unsafe {
+                libc::kill(self.pid, libc::SIGKILL);
+            }
+            let _ = self.fork.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conformance() -> Result<(), failure::Error> {
+        pty_test::conformance::run_all::<ForkPty>()
+    }
+
+    #[test]
+    fn test_concurrent_spawns() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        for _ in 0..8 {
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let result = (0..10).try_for_each(|_| {
+                    let mut pty = ForkPty::spawn(&PtyCommandBuilder::new("true"), PtySize::default())?;
+                    pty_test::conformance::read_to_end(&mut pty)?;
+                    pty.wait().map(|_| ())
+                });
+                tx.send(result.map_err(|e| e.to_string())).unwrap();
+            });
+        }
+        for _ in 0..8 {
+            let result = rx.recv_timeout(std::time::Duration::from_secs(20)).expect("spawns hung");
+            result.unwrap();
+        }
+    }
+}
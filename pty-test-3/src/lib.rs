@@ -0,0 +1,3 @@
+mod backend;
+
+pub use backend::*;
@@ -3,11 +3,12 @@ use std::io::Read;
 use std::process::{Command};
 use std::io::BufRead;
 use pty::fork::*;
+use pty_test::Utf8Decoder;
 use std::io::Write;
 
 fn main_master(mut master: Master) {
     // Read output via PTY master
-    let mut output = String::new();
+    let mut decoder = Utf8Decoder::lossy();
     let mut buffer = [0; 10];
     master.write(b"asdf\n");
     master.write(b"fdsa");
@@ -15,14 +16,11 @@ fn main_master(mut master: Master) {
     loop {
         let r = master.read(&mut buffer).unwrap();
         if r == 0 {
+            print!("{}", decoder.finish().unwrap());
             break;
         }
 
-        if let Ok(v) = utf8::decode(&buffer[..r]) {
-            print!("{}", v);
-        } else {
-            print!("a{:?}", &buffer[..r]);
-        }
+        print!("{}", decoder.push(&buffer[..r]).unwrap());
     }
 }
 
@@ -0,0 +1,106 @@
+use pty_test::{Pty, PtyCommandBuilder, PtyExitStatus, PtySize};
+use rexpect::process::wait::WaitStatus;
+use rexpect::process::PtyProcess;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+
+/// The rexpect backend.
+///
+/// `PtyProcess` turns echo off on the slave, and leaves the size at 0x0
+/// until `spawn` sets it, so a program that asks for the size straight away
+/// may see 0x0.  Dropping it sends SIGTERM until the child is gone.
+pub struct RexpectPty {
+    process: PtyProcess,
+    // a dup of the master, PtyMaster has no Read or Write
+    file: File,
+    status: Option<PtyExitStatus>,
+}
+
+impl RexpectPty {
+    pub fn process(&self) -> &PtyProcess {
+        &self.process
+    }
+}
+
+impl Pty for RexpectPty {
+    /// Only with all stdio on the pty
+    fn supports(command: &PtyCommandBuilder) -> bool {
+        command.stdio_is_pty()
+    }
+
+    fn spawn(command: &PtyCommandBuilder, size: PtySize) -> Result<Self, failure::Error> {
+        if !Self::supports(command) {
+            return Err(failure::err_msg("The rexpect backend only runs programs with all stdio on the pty"));
+        }
+
+        // PtyProcess execs the command itself, so its pre_exec runs in the child
+        let mut std_command = command.as_std_command();
+        let mut pre_exec = command.get_pre_exec().prepare()?;
+        unsafe {
+            std_command.pre_exec(move || pre_exec.apply());
+        }
+
+        // rexpect's errors aren't Sync, so they can't be wrapped
+        let process = PtyProcess::new(std_command)
+            .map_err(|e| failure::format_err!("{}", e))?;
+        pty_test::resize_fd(process.pty.as_raw_fd(), size)?;
+        let file = process.get_file_handle();
+        Ok(Self { process, file, status: None })
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.file.read(buf) {
+            // EIO means the slave has been closed
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            x => x,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn resize(&mut self, size: PtySize) -> Result<(), failure::Error> {
+        pty_test::resize_fd(self.process.pty.as_raw_fd(), size)
+    }
+
+    fn signal(&mut self, signal: libc::c_int) -> Result<(), failure::Error> {
+        if self.status.is_some() {
+            return Err(failure::err_msg("Child has already been reaped"));
+        }
+        if unsafe { libc::kill(self.process.child_pid.as_raw(), signal) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<PtyExitStatus, failure::Error> {
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        let status = loop {
+            match self.process.wait().map_err(|e| failure::format_err!("{}", e))? {
+                WaitStatus::Exited(_, code) => break PtyExitStatus::exited(code),
+                WaitStatus::Signaled(_, signal, core_dumped) => {
+                    break PtyExitStatus::signaled(signal as i32, core_dumped)
+                }
+                // stopped or continued, keep waiting for the exit
+                _ => {}
+            }
+        };
+        self.status = Some(status);
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conformance() -> Result<(), failure::Error> {
+        pty_test::conformance::run_all::<RexpectPty>()
+    }
+}
@@ -11,14 +11,15 @@ use std::pin::Pin;
 use std::os::unix::net::UnixStream;
 use std::fs::{OpenOptions, File};
 use std::borrow::BorrowMut;
-use std::os::unix::io::FromRawFd;
 use std::io::{BufReader, LineWriter};
-use std::os::unix::io::AsRawFd;
 use std::collections::HashMap;
 
 const PID_FILE: &str = "/tmp/service.pid";
 const LOG_FILE: &str = "/tmp/service.log";
 const ERR_FILE: &str = "/tmp/service.err";
+const TRANSCRIPT_FILE: &str = "/tmp/service.transcript";
+
+type Transcript = Arc<Mutex<pty_test::TranscriptWriter<File>>>;
 
 
 fn kill_daemon(pid_file: &str) {
@@ -66,18 +67,6 @@ impl Into<WaitStatus> for std::process::ExitStatus {
     }
 }
 
-impl Into<WaitStatus> for rexpect::process::wait::WaitStatus {
-    fn into(self) -> WaitStatus {
-        use rexpect::process::wait;
-        match self {
-            wait::WaitStatus::Exited(_, code) => WaitStatus::Exited(Some(code as i32)),
-            wait::WaitStatus::StillAlive => WaitStatus::Alive,
-            wait::WaitStatus::Signaled(_,_,_) => WaitStatus::Signaled,
-            _ => WaitStatus::Exited(None)
-        }
-    }
-}
-
 
 struct StdProcess {
     command: String,
@@ -91,7 +80,9 @@ struct StdProcess {
 struct ExpectProcess {
     id: ulid::Ulid,
     command: String,
-    child: rexpect::process::PtyProcess
+    child: pty_test::PtyChild,
+    // the runtime the child was spawned in, for a blocking wait
+    runtime: tokio::runtime::Handle,
 }
 
 trait Process {
@@ -102,44 +93,64 @@ trait Process {
 }
 
 impl ExpectProcess {
-    fn new(cmd: &str) -> Result<Self, failure::Error> {
+    fn new(cmd: &str, transcript: &Transcript) -> Result<Self, failure::Error> {
         let mut s = shlex::split(cmd).ok_or(failure::err_msg("Unable to parse command"))?;
         if s.len() == 0 {
             return Err(failure::err_msg("Invalid command"));
         }
         let args = s.split_off(1);
         let s_command = s.get(0).unwrap();
-        let mut command = std::process::Command::new(s_command);
+        let mut command = pty_test::PtyCommandBuilder::new(s_command);
         command.args(args);
-        let mut child = rexpect::process::PtyProcess::new(command).expect("unable to execute");
-        let fd = nix::unistd::dup(child.pty.as_raw_fd()).unwrap();
-        let f = unsafe { File::from_raw_fd(fd) };
-        let mut writer = LineWriter::new(&f);
-        let mut reader = BufReader::new(&f);
+        let (master, child) = command.spawn_pty(pty_test::PtySize::default())?;
+        // only the copying thread reads the master, and it blocks
+        let output = pty_test::PtyFd::from_fd(master.fd.fd)?;
         let id = ulid::Ulid::new();
-        Ok(ExpectProcess { command: String::from(cmd), child, id })
+        copy_to_transcript(output, id, pty_test::Stream::Pty, Arc::clone(transcript));
+        let runtime = tokio::runtime::Handle::current();
+        Ok(ExpectProcess { command: String::from(cmd), child, runtime, id })
     }
 }
 
+/// Copy the output of child `id` on `stream` into the transcript until it hangs up
+fn copy_to_transcript<R>(mut output: R, id: ulid::Ulid, stream: pty_test::Stream, transcript: Transcript)
+where
+    R: std::io::Read + Send + 'static,
+{
+    let id = id.to_string();
+    std::thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            let n = match output.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if let Err(e) = transcript.lock().unwrap().write(&id, stream, &buf[..n]) {
+                log::error!("transcript: {:?}", e);
+            }
+        }
+        if let Err(e) = transcript.lock().unwrap().finish(&id, stream) {
+            log::error!("transcript: {:?}", e);
+        }
+    });
+}
+
 impl Process for ExpectProcess {
     fn try_wait(&mut self) -> Option<WaitStatus> {
-        use rexpect::process::wait;
-        match self.child.status() {
-            Some(w) => Some(w.into()),
-            None => None
+        match self.child.try_wait() {
+            Ok(Some(e)) => Some(e.into()),
+            Ok(None) => Some(WaitStatus::Alive),
+            Err(e) => None
         }
     }
     fn wait(&mut self) -> std::io::Result<WaitStatus> {
-        if let Ok(w) = self.child.wait() {
-            return Ok(w.into())
-        }
-        panic!();
+        let child = &mut self.child;
+        Ok(self.runtime.block_on(child.wait())?.into())
     }
     fn kill(&mut self) -> std::io::Result<()> {
-        match self.child.signal(rexpect::process::signal::Signal::SIGTERM) {
-            Ok(()) => Ok(()),
-            Err(e) => panic!()
-        }
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        Ok(kill(Pid::from_raw(self.child.id()), Signal::SIGTERM)?)
     }
     fn get_command(&self) -> &str {
         self.command.as_str()
@@ -147,7 +158,7 @@ impl Process for ExpectProcess {
 }
 
 impl StdProcess {
-    fn new_std(cmd: &str) -> Result<Self, failure::Error> {
+    fn new_std(cmd: &str, transcript: &Transcript) -> Result<Self, failure::Error> {
         let mut s = shlex::split(cmd).ok_or(failure::err_msg("Unable to parse command"))?;
         if s.len() == 0 {
             return Err(failure::err_msg("Invalid command"));
@@ -159,13 +170,19 @@ impl StdProcess {
         let (stdout_a, stdout_b) = UnixStream::pair().unwrap();
         let (stderr_a, stderr_b) = UnixStream::pair().unwrap();
 
+        use std::os::unix::io::OwnedFd;
+        use std::process::Stdio;
         let mut child = std::process::Command::new(s_command).args(args)
             //.stdin(stdin_a.as_stdio())
-            //.stdout(stdout_a)
-            //.stderr(stderr_a)
+            .stdout(Stdio::from(OwnedFd::from(stdout_a)))
+            .stderr(Stdio::from(OwnedFd::from(stderr_a)))
             .spawn()?;
 
+        // our copies of the child's ends went with the Command, so the
+        // copying ends when the child closes them
         let id = ulid::Ulid::new();
+        copy_to_transcript(stdout_b.try_clone()?, id, pty_test::Stream::Stdout, Arc::clone(transcript));
+        copy_to_transcript(stderr_b.try_clone()?, id, pty_test::Stream::Stderr, Arc::clone(transcript));
         Ok(StdProcess { command: String::from(cmd), id, child, stdin: stdin_b, stdout: stdout_b, stderr: stderr_b })
     }
 }
@@ -229,6 +246,11 @@ fn main() -> Result<(), failure::Error> {
     }
     log::info!("service started");
 
+    // the pty children are tokio processes, started after daemonizing
+    // so that the runtime's threads survive the fork
+    let runtime = tokio::runtime::Runtime::new()?;
+    let _runtime = runtime.enter();
+
     // for termination signalling
     let term = Arc::new(AtomicBool::new(false));
 
@@ -236,15 +258,27 @@ fn main() -> Result<(), failure::Error> {
     signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
     signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&term))?;
 
+    // wakes the main loop when a child exits or we're told to stop, set up
+    // before any child is spawned so that no exit is missed
+    let mut signals = signal_hook::iterator::Signals::new(&[
+        signal_hook::consts::SIGCHLD,
+        signal_hook::consts::SIGTERM,
+        signal_hook::consts::SIGINT,
+        signal_hook::consts::SIGHUP,
+    ])?;
+
+    let transcript = File::create(TRANSCRIPT_FILE)?;
+    let transcript: Transcript = Arc::new(Mutex::new(pty_test::TranscriptWriter::new(transcript)));
+
     let mut children: HashMap<ulid::Ulid, Box<dyn Process>> = HashMap::new();
 
     for i in 0..20 {
-        let p = StdProcess::new_std("sleep 6").unwrap();
+        let p = StdProcess::new_std("sleep 6", &transcript).unwrap();
         children.insert(p.id, Box::new(p));
     }
 
     for i in 0..20 {
-        let p = ExpectProcess::new("sleep 5").unwrap();
+        let p = ExpectProcess::new("sleep 5", &transcript).unwrap();
         children.insert(p.id, Box::new(p));
     }
 
@@ -273,7 +307,8 @@ fn main() -> Result<(), failure::Error> {
                 }
             }
         });
-        std::thread::sleep(std::time::Duration::from_millis(200));
+        // block until the next signal, those that came in meanwhile are returned at once
+        signals.wait().count();
     }
 
     // on exit, send kill, then wait to reap the child